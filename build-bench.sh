@@ -0,0 +1,14 @@
+#!/bin/bash
+
+set -euxo pipefail
+
+TARGET=wasm32-unknown-unknown
+NAME=toothgame
+BINARY=target/$TARGET/release/$NAME.wasm
+DIST=www/${NAME}_bench.wasm
+
+cargo build --release --target $TARGET --features "bench"
+cp $BINARY $DIST
+wasm-strip $DIST
+wasm-opt -o $DIST -Oz $DIST
+ls -l $DIST
@@ -13,7 +13,13 @@ use glium::glutin::event::VirtualKeyCode as KeyCode;
 use std::collections::HashSet;
 
 struct LevelLand {
-    payload: [u8; 5]
+    chunk: terrain::LandChunk
+}
+
+struct LevelLayer {
+    layer: u8,
+    scroll: Vec2<u8>,
+    area: Vec<u8>,
 }
 
 struct LevelTerrain {
@@ -24,26 +30,47 @@ impl LevelTerrain {
     pub fn id(&self) -> u8 {
         self.payload[0]
     }
-    pub fn y(&self) -> u8 {
-        self.payload[1]
+    pub fn y(&self) -> u16 {
+        u16::from_be_bytes([self.payload[1], self.payload[2]])
     }
-    pub fn x(&self) -> u8 {
-        self.payload[2]
+    pub fn x(&self) -> u16 {
+        u16::from_be_bytes([self.payload[3], self.payload[4]])
     }
     pub fn params(&self) -> u8 {
-        self.payload[3]
+        self.payload[5]
     }
     pub fn id_mut(&mut self) -> &mut u8 {
         &mut self.payload[0]
     }
-    pub fn y_mut(&mut self) -> &mut u8 {
-        &mut self.payload[1]
+    pub fn set_y(&mut self, y: u16) {
+        self.payload[1..3].copy_from_slice(&y.to_be_bytes());
     }
-    pub fn x_mut(&mut self) -> &mut u8 {
-        &mut self.payload[2]
+    pub fn set_x(&mut self, x: u16) {
+        self.payload[3..5].copy_from_slice(&x.to_be_bytes());
     }
     pub fn params_mut(&mut self) -> &mut u8 {
-        &mut self.payload[3]
+        &mut self.payload[5]
+    }
+    /// Whether the object takes a whole byte for each parameter.
+    pub fn is_wide(&self) -> bool {
+        self.id() & terrain::WIDE_PARAMS != 0
+    }
+    /// The second parameter byte of a wide object.
+    pub fn wide_param_mut(&mut self) -> &mut u8 {
+        if self.payload.len() < 7 { self.payload.resize(7, 0); }
+        &mut self.payload[6]
+    }
+    /// Adds to one of the parameters, `0` being the high nibble (or first byte, for wide objects).
+    pub fn adjust_param(&mut self, which: usize, delta: i8) {
+        match (self.is_wide(), which) {
+            (true, 0) => *self.params_mut() = self.params().wrapping_add(delta as u8),
+            (true, _) => {
+                let param = self.wide_param_mut();
+                *param = param.wrapping_add(delta as u8);
+            }
+            (false, 0) => *self.params_mut() = self.params().wrapping_add((delta as u8) << 4),
+            (false, _) => *self.params_mut() = (self.params() & 0xF0) | (self.params().wrapping_add(delta as u8) & 0x0F),
+        }
     }
 }
 
@@ -84,8 +111,21 @@ pub struct EditorState {
 }
 
 pub struct Editor {
+    header: terrain::LevelHeader,
     level_land: Vec<LevelLand>,
     level_terrain: Vec<LevelTerrain>,
+    /// Scroll factor of the main layer, kept as it was read.
+    main_scroll: Vec2<u8>,
+    /// Layers other than the main one, which the editor keeps but can't change.
+    other_layers: Vec<LevelLayer>,
+    /// Every area of the level, encoded, as of when the editor last switched areas.
+    areas: Vec<Vec<u8>>,
+    /// Index of the area being edited.
+    area: usize,
+    /// Warps of the area being edited, which the editor keeps but can't change.
+    warps: Vec<terrain::Warp>,
+    /// Entities placed in the area being edited, which the editor also keeps as they are.
+    spawns: Vec<terrain::Spawn>,
     foreground: foreground::Foreground,
     mouse_pos: Vec2<i32>,
     last_mouse: Vec2<i32>,
@@ -93,6 +133,8 @@ pub struct Editor {
     camera: Vec2<i32>,
     rerender: bool,
     rebuild: bool,
+    /// The first problem found while placing the level, shown until it's fixed.
+    error: Option<String>,
     keys: HashSet<KeyCode>,
 
     state: EditorState
@@ -102,8 +144,15 @@ impl Editor {
     pub fn new() -> Self {
         let mut foreground = foreground::Foreground::new();
         Self {
+            header: Default::default(),
             level_land: vec![],
             level_terrain: vec![],
+            main_scroll: vec2(16, 16),
+            other_layers: vec![],
+            areas: vec![vec![]],
+            area: 0,
+            warps: vec![],
+            spawns: vec![],
             foreground,
             camera: vec2(0, 0),
             mouse_pos: vec2(0, 0),
@@ -111,6 +160,7 @@ impl Editor {
             dragging: false,
             rerender: true,
             rebuild: true,
+            error: None,
             keys: Default::default(),
 
             state: EditorState::default()
@@ -154,6 +204,38 @@ impl Editor {
                             grabbed_object: None,
                         },
                         P if pressed => self.state.mode = EditorMode::Preview,
+                        N if pressed => {
+                            self.store_area();
+                            let next = (self.area + 1) % self.areas.len();
+                            if let Err(e) = self.read_area(next) {
+                                self.error = Some(format!("area {}: {}", next, e));
+                            }
+                        }
+                        T if pressed => {
+                            self.header.tileset = (self.header.tileset + 1) % graphics::TILESETS.len() as u8;
+                            self.foreground.set_tileset(self.header.tileset);
+                        }
+                        E if pressed => {
+                            self.header.effects = (self.header.effects + 1) % graphics::RASTER_EFFECTS.len() as u8;
+                        }
+                        // Raise the water line, bringing it in from the bottom of the area
+                        LBracket if pressed => {
+                            let bottom = self.header.size.y;
+                            self.header.water_level = Some(self.header.water_level.unwrap_or(bottom).saturating_sub(1));
+                        }
+                        // Lower the water line, taking it out past the bottom of the area
+                        RBracket if pressed => {
+                            let bottom = self.header.size.y;
+                            self.header.water_level = self.header.water_level.map(|c| c + 1).filter(|&c| c < bottom);
+                        }
+                        // Move where the player starts to the block under the mouse
+                        S if pressed => {
+                            let block = (self.mouse_pos + self.camera) / 16;
+                            let size = self.header.size.map(|c| c as i32);
+                            if block.x >= 0 && block.y >= 0 && block.x < size.x && block.y < size.y {
+                                self.header.start = block.map(|c| c as u16);
+                            }
+                        }
                         _ => {},
                     } if pressed { self.keys.insert(c); } }
                     self.rerender = true;
@@ -165,6 +247,11 @@ impl Editor {
             self.camera += self.last_mouse - self.mouse_pos;
             self.rerender = true;
         }
+        if matches!(self.state.mode, EditorMode::Preview) {
+            // keep animated tiles moving
+            self.foreground.tick();
+            self.rerender = true;
+        }
         let redraw_frame = self.rerender;
         self.last_mouse = self.mouse_pos;
         if self.rerender {
@@ -192,19 +279,22 @@ impl Editor {
                 *grabbed_object = None;
             }
             if self.keys.contains(&KeyCode::A) {
-                let wp = (world_pos / 16).map(|c| c as u8);
-                self.level_terrain.push(LevelTerrain {
-                    payload: vec![0x01, wp.y, wp.x, 0x22],
-                    decoded_len: 4,
-                });
+                let wp = (world_pos / 16).map(|c| c as u16);
+                let mut object = LevelTerrain {
+                    payload: vec![0x01, 0, 0, 0, 0, 0x22],
+                    decoded_len: 6,
+                };
+                object.set_x(wp.x);
+                object.set_y(wp.y);
+                self.level_terrain.push(object);
                 self.rebuild = true;
             }
             let mut action_id = None;
             for (id, obj) in self.level_terrain.iter_mut().enumerate() {
                 if let Some(c) = grabbed_object.as_ref() {
                     if c.id == id {
-                        *obj.x_mut() = (world_pos.x / 16) as u8;
-                        *obj.y_mut() = (world_pos.y / 16) as u8;
+                        obj.set_x((world_pos.x / 16) as u16);
+                        obj.set_y((world_pos.y / 16) as u16);
                         self.rebuild = true;
                     }
                 }
@@ -223,7 +313,12 @@ impl Editor {
                 }
 
                 let mut pos = vec2(obj.x() as i32, obj.y() as i32) * 16 - self.camera;
-                graphics::draw_text(fb, &mut pos, format!("{:02X}\n{:02X}", obj.id(), obj.params()).as_bytes());
+                let params = if obj.is_wide() {
+                    format!("{:02X}{:02X}", obj.params(), obj.payload.get(6).copied().unwrap_or(0))
+                } else {
+                    format!("{:02X}", obj.params())
+                };
+                graphics::draw_text(fb, &mut pos, format!("{:02X}\n{}", obj.id(), params).as_bytes());
             }
             if let Some((id, offset)) = action_id {
                 if self.keys.contains(&KeyCode::Delete) {
@@ -246,8 +341,8 @@ impl Editor {
                     match quadrant {
                         0 => *item.id_mut() = item.id().wrapping_sub(16),
                         1 => *item.id_mut() = item.id().wrapping_sub(1),
-                        2 => *item.params_mut() = item.params().wrapping_sub(16),
-                        3 => *item.params_mut() = (item.params() & 0xF0) | (item.params().wrapping_sub(1) & 0x0F),
+                        2 => item.adjust_param(0, -1),
+                        3 => item.adjust_param(1, -1),
                         _ => {}
                     }
                     self.rebuild = true;
@@ -258,8 +353,8 @@ impl Editor {
                     match quadrant {
                         0 => *item.id_mut() = item.id().wrapping_add(16),
                         1 => *item.id_mut() = item.id().wrapping_add(1),
-                        2 => *item.params_mut() = item.params().wrapping_add(16),
-                        3 => *item.params_mut() = (item.params() & 0xF0) | (item.params().wrapping_add(1) & 0x0F),
+                        2 => item.adjust_param(0, 1),
+                        3 => item.adjust_param(1, 1),
                         _ => {}
                     }
                     self.rebuild = true;
@@ -281,29 +376,31 @@ impl Editor {
             let world_pos = self.mouse_pos + self.camera;
 
             let mut delete_id = None;
+            let materials = self.header.land_materials();
             if self.keys.contains(&KeyCode::A) {
-                let wp = (world_pos / 16).map(|c| c as u8);
+                let wp = (world_pos / 16).map(|c| c as u16);
+                let block = materials.first().map_or(0x62, |c| c.block);
                 self.level_land.push(LevelLand {
-                    payload: [wp.x, wp.y, 3, 3, 0x62]
+                    chunk: terrain::LandChunk { x: wp.x, y: wp.y, w: 3, h: 3, block }
                 });
                 self.rebuild = true;
             }
             if self.keys.contains(&KeyCode::S) {
-                let wp = (world_pos / 16).map(|c| c as u8);
+                let wp = (world_pos / 16).map(|c| c as u16);
                 self.level_land.push(LevelLand {
-                    payload: [wp.x, wp.y, 3, 3, 0x00]
+                    chunk: terrain::LandChunk { x: wp.x, y: wp.y, w: 3, h: 3, block: 0x00 }
                 });
                 self.rebuild = true;
             }
-            for (id,payload) in self.level_land.iter_mut().enumerate().map(|(i,c)| (i, &mut c.payload)) {
-                let [ref mut x,ref mut y,ref mut w,ref mut h,ref mut b] = payload;
+            for (id,chunk) in self.level_land.iter_mut().enumerate().map(|(i,c)| (i, &mut c.chunk)) {
+                let terrain::LandChunk { ref mut x, ref mut y, ref mut w, ref mut h, .. } = chunk;
                 let mut grabbed = false;
                 if let Some(c) = grabbed_land.as_ref() {
                     if c.id == id {
                         grabbed = true;
                         let world_pos = self.mouse_pos + self.camera;
-                        let offset = (world_pos / 16).map(|c| c as u8);
-                        let offset2 = ((world_pos - c.offset) / 16).map(|c| c as u8);
+                        let offset = (world_pos / 16).map(|c| c as u16);
+                        let offset2 = ((world_pos - c.offset) / 16).map(|c| c as u16);
                         if c.corners == 15 {
                             *x = offset2.x;
                             *y = offset2.y;
@@ -344,6 +441,14 @@ impl Editor {
                 if x_range && y_range && self.keys.contains(&KeyCode::Delete) {
                     delete_id = Some(id);
                 }
+                // M paints the chunk with the next material, empty chunks stay empty
+                if x_range && y_range && self.keys.contains(&KeyCode::M) && chunk.block != 0 {
+                    let next = materials.iter().position(|c| c.block == chunk.block).map_or(0, |c| c + 1);
+                    if let Some(material) = materials.get(next).or_else(|| materials.first()) {
+                        chunk.block = material.block;
+                    }
+                    self.rebuild = true;
+                }
                 let color = if grabbed {
                     0xFF008080
                 } else {
@@ -372,7 +477,8 @@ impl Editor {
             let transp = (pos / 4) & 1;
             let transp = if transp.x ^ transp.y == 0 { 0xFF111111 } else { 0xFF222222 };
             let pos = pos + self.camera;
-            if pos.x >= 0 && pos.y >= 0 && pos.x < 4096 && pos.y < 4096 {
+            let level_size = self.foreground.size() * 16;
+            if pos.x >= 0 && pos.y >= 0 && pos.x < level_size.x && pos.y < level_size.y {
                 if (pos.x % 256 == 0 || pos.y % 256 == 0) && !matches!(self.state.mode, EditorMode::Preview) {
                     *c = 0xFF808080;
                 } else {
@@ -382,11 +488,17 @@ impl Editor {
                 *c = transp;
             }
         }
+        if let Some(row) = self.header.water_level {
+            let y = row as i32 * 16 - self.camera.y;
+            for x in 0..width as i32 {
+                fb.pixel(vec2(x, y)).map(|c| *c = 0xFFFF8040);
+            }
+        }
         self.edit_land(fb);
         self.edit_objects(fb);
         let mut pos = self.mouse_pos + vec2(0,10);
         let block = (self.mouse_pos + self.camera) / 16;
-        if block.map(|c| c >= 0 && c < 256) == vec2(true,true) {
+        if block.zip(self.foreground.size(), |c, m| c >= 0 && c < m) == vec2(true,true) {
             let x = block.x * 16;
             let y = block.y * 16;
             let w = 16; let h = 16;
@@ -400,42 +512,144 @@ impl Editor {
             }
             graphics::draw_text(fb, &mut pos, format!("{:02X},{:02X}", block.x, block.y).as_bytes());
         }
+        if !matches!(self.state.mode, EditorMode::Preview) {
+            for warp in self.warps.iter() {
+                let width = match warp.kind { terrain::WarpKind::Door => 16, terrain::WarpKind::Pipe => 32 };
+                let pos = warp.pos.map(|c| c as i32) * 16 - self.camera;
+                for i in 0..width {
+                    fb.pixel(pos + vec2(i, 0)).map(|c| *c = 0xFFFF00FF);
+                    fb.pixel(pos + vec2(i, 15)).map(|c| *c = 0xFFFF00FF);
+                }
+                graphics::draw_text(fb, &mut (pos + vec2(2, 4)), format!("{}", warp.area).as_bytes());
+            }
+            let pos = self.header.start.map(|c| c as i32) * 16 - self.camera;
+            for i in 0..16 {
+                fb.pixel(pos + vec2(i, 0)).map(|c| *c = 0xFF00FF00);
+                fb.pixel(pos + vec2(i, 15)).map(|c| *c = 0xFF00FF00);
+            }
+            graphics::draw_text(fb, &mut (pos + vec2(2, 4)), b"S");
+        }
+        graphics::draw_text(fb, &mut vec2(8, 8), format!(
+            "AREA {}/{}  EFFECTS {}  WATER {}",
+            self.area, self.areas.len(), self.header.effects,
+            self.header.water_level.map_or("NONE".to_string(), |c| c.to_string())
+        ).as_bytes());
+        if let Some(error) = &self.error {
+            graphics::draw_text(fb, &mut vec2(8, 540-32), error.to_uppercase().as_bytes());
+        }
     }
     pub fn read_level(&mut self, src: &[u8]) -> Result<(), String> {
-        self.clear_blocks();
+        self.areas.clear();
+        for area in terrain::decode_level_areas(src).map_err(|e| e.to_string())? {
+            let area = area.map_err(|e| format!("area {}: {}", self.areas.len(), e))?;
+            self.areas.push(area.encoded().to_vec());
+        }
+        if self.areas.is_empty() {
+            return Err("level has no areas".to_string());
+        }
+        self.read_area(0)
+    }
+    /// Switches to editing another area of the level, which must have been stored first.
+    fn read_area(&mut self, id: usize) -> Result<(), String> {
+        let src = self.areas[id].clone();
+        let area = terrain::decode_level_area(&mut &src[..]).map_err(|e| e.to_string())?;
+        self.area = id;
+        self.header = area.header;
+        self.warps = area.warps().collect();
+        self.spawns = area.spawns().collect();
+        self.foreground.set_tileset(self.header.tileset);
+        self.foreground.set_size(self.header.size.map(|c| c as i32));
+        for &id in [foreground::LayerId::Back, foreground::LayerId::Front].iter() {
+            self.foreground.layer_mut(id).visible = false;
+        }
+        self.level_land.clear();
+        self.level_terrain.clear();
+        self.other_layers.clear();
+        for section in area.sections() {
+            if section.layer == foreground::LayerId::Main as u8 {
+                self.main_scroll = section.scroll;
+                self.read_main_layer(section.area).map_err(|e| format!("main layer: {}", e))?;
+            } else {
+                if let Some(id) = foreground::LayerId::from_u8(section.layer) {
+                    let layer = self.foreground.layer_mut(id);
+                    layer.visible = true;
+                    layer.scroll = section.scroll.map(|c| c as i32 * 16);
+                    let mut map = self.foreground.layer_map(id)
+                        .ok_or_else(|| format!("layer {}: no room left for its blocks", section.layer))?;
+                    match terrain::decode_area(&mut map, section.area, &self.header) {
+                        Ok(()) | Err(terrain::DecodeError::OutOfBounds { .. }) => {}
+                        Err(e) => return Err(format!("layer {}: {}", section.layer, e)),
+                    }
+                }
+                self.other_layers.push(LevelLayer {
+                    layer: section.layer,
+                    scroll: section.scroll,
+                    area: section.area.to_vec()
+                });
+            }
+        }
+        self.rebuild = true;
+        Ok(())
+    }
+    /// Reads the land and objects of the main layer, keeping things that reach outside the level so they can be moved back.
+    fn read_main_layer(&mut self, src: &[u8]) -> Result<(), terrain::DecodeError> {
+        if src.len() < 2 { return Err(terrain::DecodeError::Truncated); }
         let len = ((src[0] as usize) << 8) + src[1] as usize;
         let src = &src[2..];
-        self.level_land.clear();
+        if len > src.len() { return Err(terrain::DecodeError::LengthMismatch); }
         {
             let mut src = &src[..len];
-            terrain::decode_land(self.foreground.blocks_mut(), &src);
-            while src.len() != 0 {
-                self.level_land.push(LevelLand { payload: terrain::decode_land_chunk(&mut src) });
+            while !src.is_empty() {
+                self.level_land.push(LevelLand { chunk: terrain::decode_land_chunk(&mut src, &self.header)? });
             }
         }
         let mut src = &src[len..];
-        self.level_terrain.clear();
-        while src.len() != 0 {
-            let old = &src[..];
-            terrain::decode_object(self.foreground.blocks_mut(), &mut src);
+        while !src.is_empty() {
+            let old = src;
+            match terrain::decode_object(&mut self.foreground.block_map(), &mut src, &self.header) {
+                Ok(()) | Err(terrain::DecodeError::OutOfBounds { .. }) => {}
+                Err(e) => return Err(e),
+            }
             let len = src.as_ptr() as usize - old.as_ptr() as usize;
             self.level_terrain.push(LevelTerrain {
                 payload: old[..len].to_vec(),
                 decoded_len: len
             });
         }
+        self.rebuild = true;
         Ok(())
     }
     pub fn write_level(&mut self) -> Result<Vec<u8>, String> {
-        let mut buf = vec![];
-        buf.extend_from_slice(&((self.level_land.len() * 5) as u16).to_be_bytes());
+        self.store_area();
+        let mut buf = vec![self.areas.len() as u8];
+        for i in self.areas.iter() {
+            buf.extend_from_slice(i);
+        }
+        Ok(buf)
+    }
+    /// Encodes the area being edited back into the level.
+    fn store_area(&mut self) {
+        let mut land = vec![];
         for i in self.level_land.iter() {
-            buf.extend_from_slice(&i.payload);
+            i.chunk.encode(&self.header, |c| land.push(c));
         }
+        let mut area = vec![];
+        area.extend_from_slice(&(land.len() as u16).to_be_bytes());
+        area.extend_from_slice(&land);
         for i in self.level_terrain.iter() {
-            buf.extend_from_slice(&i.payload[..i.decoded_len]);
+            area.extend_from_slice(&i.payload[..i.decoded_len]);
         }
-        Ok(buf)
+        let mut sections = vec![terrain::LayerSection {
+            layer: foreground::LayerId::Main as u8,
+            scroll: self.main_scroll,
+            area: &area
+        }];
+        for i in self.other_layers.iter() {
+            sections.push(terrain::LayerSection { layer: i.layer, scroll: i.scroll, area: &i.area });
+        }
+        let mut buf = vec![];
+        terrain::encode_level_area(&self.header, &self.warps, &self.spawns, &sections, |c| buf.push(c));
+        self.areas[self.area] = buf;
     }
     pub fn clear_blocks(&mut self) {
         for i in self.foreground.blocks_mut().iter_mut() {
@@ -444,16 +658,23 @@ impl Editor {
     }
     pub fn rebuild(&mut self) {
         self.clear_blocks();
-        terrain::decode_land_with(
-            self.foreground.blocks_mut(),
-            self.level_land.iter().map(|c| c.payload)
+        let land = terrain::decode_land_with(
+            &mut self.foreground.block_map(),
+            self.header.land_materials(),
+            self.level_land.iter().map(|c| c.chunk)
         );
-        for i in self.level_terrain.iter_mut() {
+        self.error = land.err().map(|e| format!("land: {}", e));
+        for (n, i) in self.level_terrain.iter_mut().enumerate() {
             let mut buf = [0; 256];
             buf[..i.payload.len()].copy_from_slice(&i.payload);
             let mut src = &buf[..];
-            terrain::decode_object(self.foreground.blocks_mut(), &mut src);
+            let res = terrain::decode_object(&mut self.foreground.block_map(), &mut src, &self.header);
+            if let Err(e) = res {
+                self.error.get_or_insert_with(|| format!("object {}: {}", n, e));
+            }
             i.decoded_len = buf.len() - src.len();
+            // Changing the id can change how many bytes the object takes
+            i.payload.resize(i.decoded_len, 0);
         }
     }
     /*
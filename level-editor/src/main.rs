@@ -70,7 +70,9 @@ fn main() {
 
     let mut editor = Editor::new();
     //editor.read_text(&String::from_utf8(std::fs::read("level.txt").unwrap()).unwrap());
-    editor.read_level(&std::fs::read("../level_demo.bin").unwrap());
+    if let Err(e) = editor.read_level(&std::fs::read("../level_demo.bin").unwrap()) {
+        eprintln!("couldn't read the level: {}", e);
+    }
     //println!("{}", editor.write_text());
     let mut events = vec![];
     let data = unsafe {
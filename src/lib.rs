@@ -32,3 +32,22 @@ pub static mut SND: [f32; 1024] = [0.0; 1024];
 pub unsafe fn snd() {
     SND.copy_from_slice(&[0.0; 1024]);
 }
+
+/// Draws `frames` frames of the foreground, panning across the level, for www/bench.html to time.
+///
+/// Uses the old per-pixel renderer if `sampled` isn't 0.
+#[cfg(feature = "bench")]
+#[no_mangle]
+pub unsafe fn bench(frames: i32, sampled: u32) {
+    let fb = &mut BUF;
+    let fg = &state::get().unwrap_level().foreground;
+    let range = fg.size() * 16 - Framebuffer::size();
+    for i in 0..frames {
+        let camera = vec2::vec2(range.x * i / frames, range.y * i / frames);
+        if sampled != 0 {
+            fg.render_sampled(camera, fb);
+        } else {
+            fg.render(camera, &raster::Scanlines::flat(camera), fb);
+        }
+    }
+}
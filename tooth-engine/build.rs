@@ -19,6 +19,12 @@ impl DataDef {
     }
 }
 
+struct TileShape {
+    heights: [i8; 16],
+    widths: [i8; 16],
+    angle: i8,
+}
+
 fn main() {
     let out_dir = env::var("OUT_DIR").unwrap();
     let dest_path = Path::new(&out_dir).join("gfx.rs");
@@ -28,14 +34,55 @@ fn main() {
     let mut data = vec![];
     let mut pal = vec![];
 
-    let dune_bg = image::open("assets/bg/dune.png").unwrap().into_rgba();
-    embed_bg(&dune_bg, &mut data, &mut pal).write(&mut f, "DUNE_BG");
-    let dune_fg = image::open("assets/fg/dune.png").unwrap().into_rgba();
-    embed_fg(&dune_fg, &mut data, &mut pal).write(&mut f, "DUNE_FG");
+    // Every definition in `assets/bg` is a background theme, every image in `assets/fg` a tileset
+    let mut backgrounds = vec![];
+    for name in asset_names("assets/bg", "txt") {
+        let ident = format!("{}_BG", name.to_uppercase());
+        let src = std::fs::read_to_string(format!("assets/bg/{}.txt", name)).unwrap();
+        let (sky, layers, effects) = background_layers(&src, &mut data, &mut pal);
+        writeln!(f, "pub static {}_LAYERS: [BgLayer; {}] = [{}];", ident, layers.len(), layers.join(", ")).unwrap();
+        backgrounds.push(format!(
+            "BackgroundDef {{ sky: [0x{:08X}, 0x{:08X}], layers: &{}_LAYERS, effects: &[{}] }}",
+            sky[0], sky[1], ident, effects.join(", ")
+        ));
+    }
+    let effects = raster_effect_sets(&std::fs::read_to_string("assets/effects.txt").unwrap());
+    writeln!(f, "pub static RASTER_EFFECTS: [&[RasterEffect]; {}] = [{}];", effects.len(), effects.join(", ")).unwrap();
+    let mut tilesets = vec![];
+    for name in asset_names("assets/fg", "png") {
+        let img = image::open(format!("assets/fg/{}.png", name)).unwrap().into_rgba8();
+        let ident = format!("{}_FG", name.to_uppercase());
+        let gfx = embed_fg(&img, &mut data, &mut pal);
+        gfx.write(&mut f, &ident);
+        let cycles = color_cycles(&std::fs::read_to_string(format!("assets/fg/{}_palette.txt", name)).unwrap(), &gfx, &pal);
+        writeln!(f, "pub static {}_CYCLES: [ColorCycle; {}] = [{}];", ident, cycles.len(), cycles.join(", ")).unwrap();
+        let mask = image::open(format!("assets/fg/{}_mask.png", name)).unwrap().into_rgba8();
+        let shapes = tile_shapes(&mask);
+        let shapes: Vec<String> = shapes.iter()
+            .map(|c| format!("TileShape {{ heights: {:?}, widths: {:?}, angle: {} }}", c.heights, c.widths, c.angle))
+            .collect();
+        writeln!(f, "pub static {}_SHAPES: [TileShape; {}] = [{}];", ident, shapes.len(), shapes.join(", ")).unwrap();
+        let count = (img.width() / 16 * (img.height() / 16)) as usize;
+        let props = tile_props(&std::fs::read_to_string(format!("assets/fg/{}_tiles.txt", name)).unwrap(), count);
+        writeln!(f, "pub static {}_PROPS: [TileProps; {}] = [{}];", ident, props.len(), props.join(", ")).unwrap();
+        let land = land_materials(&std::fs::read_to_string(format!("assets/fg/{}_land.txt", name)).unwrap());
+        writeln!(f, "pub static {}_LAND: [LandMaterial; {}] = [{}];", ident, land.len(), land.join(", ")).unwrap();
+        tilesets.push(ident);
+    }
+    writeln!(f, "pub static BACKGROUNDS: [BackgroundDef; {}] = [{}];", backgrounds.len(), backgrounds.join(", ")).unwrap();
+    let messages = messages(&std::fs::read_to_string("assets/messages.txt").unwrap());
+    writeln!(f, "pub static MESSAGES: [&[u8]; {}] = [{}];", messages.len(), messages.join(", ")).unwrap();
+    let tilesets: Vec<String> = tilesets.iter()
+        .map(|c| format!("Tileset {{ gfx: {0}, shapes: &{0}_SHAPES, props: &{0}_PROPS, land: &{0}_LAND, cycles: &{0}_CYCLES }}", c))
+        .collect();
+    writeln!(f, "pub static TILESETS: [Tileset; {}] = [{}];", tilesets.len(), tilesets.join(", ")).unwrap();
     let img = image::open("assets/sprites/toothpaste.png").unwrap().into_rgba();
     embed_fg(&img, &mut data, &mut pal).write(&mut f, "TOOTHPASTE");
     let img = image::open("assets/sprites/misc.png").unwrap().into_rgba();
-    embed_fg(&img, &mut data, &mut pal).write(&mut f, "MISC");
+    let misc = embed_fg(&img, &mut data, &mut pal);
+    misc.write(&mut f, "MISC");
+    let variants = palette_variants(&std::fs::read_to_string("assets/sprites/misc_palettes.txt").unwrap(), &misc, &mut pal);
+    writeln!(f, "pub static MISC_VARIANTS: [DataDef; {}] = [MISC, {}];", variants.len() + 1, variants.join(", ")).unwrap();
     let img = image::open("assets/font/boldface.png").unwrap().into_rgba();
     embed_font(&img, &mut data).write(&mut f, "BOLDFACE");
 
@@ -47,6 +94,7 @@ fn main() {
     writeln!(f, r#"pub static mut GFX_DATA: [u8; {0:}] = [0; {0:}];"#, data.len()).unwrap();
     writeln!(f, r#"pub static GFX_DATA_LZ4: [u8; {}] = *include_bytes!(concat!(env!("OUT_DIR"), "/gfx.bin"));"#, comp.len()).unwrap();
     writeln!(f, "pub static PAL_DATA: [u32; {}] = {:?};", pal.len(), pal).unwrap();
+    writeln!(f, "pub static mut PALETTE: [u32; {0:}] = [0; {0:}];", pal.len()).unwrap();
 
     //panic!();
 
@@ -54,6 +102,18 @@ fn main() {
     //eprintln!("{:X?}", pal);
 }
 
+/// Lists the files in a directory with an extension by name, in a stable order, skipping collision masks.
+fn asset_names(dir: &str, extension: &str) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir).unwrap()
+        .map(|c| c.unwrap().path())
+        .filter(|c| c.extension() == Some(extension.as_ref()))
+        .map(|c| c.file_stem().unwrap().to_str().unwrap().to_string())
+        .filter(|c| !c.ends_with("_mask"))
+        .collect();
+    names.sort();
+    names
+}
+
 fn embed_bg(image: &image::RgbaImage, data: &mut Vec<u8>, pal: &mut Vec<u32>) -> DataDef {
     let mut palette = HashMap::new();
     let offset = data.len();
@@ -114,3 +174,326 @@ fn embed_font(image: &image::RgbaImage, data: &mut Vec<u8>) -> DataDef {
         end: data.len()
     }
 }
+
+/// Measures the collision shape of every tile in a mask, where opaque pixels are solid.
+fn tile_shapes(image: &image::RgbaImage) -> Vec<TileShape> {
+    let mut shapes = vec![];
+    for ty in 0..image.height()/16 {
+        for tx in 0..image.width()/16 {
+            let solid = |x: u32, y: u32| image.get_pixel(tx*16 + x, ty*16 + y).0[3] >= 0x80;
+            // Counts solid pixels from the far end of a line, or from the near end as a negative number
+            let measure = |line: &dyn Fn(u32) -> bool| -> i8 {
+                let far = (0..16).rev().take_while(|&i| line(i)).count() as i8;
+                let near = (0..16).take_while(|&i| line(i)).count() as i8;
+                if far > 0 { far } else { -near }
+            };
+            let mut heights = [0; 16];
+            let mut widths = [0; 16];
+            for i in 0..16 {
+                heights[i as usize] = measure(&|y| solid(i, y));
+                widths[i as usize] = measure(&|x| solid(x, i));
+            }
+            // Slope of the edge facing open space, between the outermost non-empty columns
+            let is_ceiling = heights.iter().any(|&h| h < 0);
+            let edge: Vec<(i32, i32)> = heights.iter().enumerate()
+                .filter(|(_, &h)| h != 0)
+                .map(|(x, &h)| (x as i32, if is_ceiling { -h as i32 } else { 16 - h as i32 }))
+                .collect();
+            let angle = match (edge.first(), edge.last()) {
+                (Some(&(x0, y0)), Some(&(x1, y1))) if x1 > x0 => (2.0 * (y1 - y0) as f32 / (x1 - x0) as f32).round() as i8,
+                _ => 0
+            };
+            shapes.push(TileShape { heights, widths, angle });
+        }
+    }
+    shapes
+}
+
+/// Parses a tileset's block properties into `TileProps` expressions, one for each of its `count` blocks.
+fn tile_props(src: &str, count: usize) -> Vec<String> {
+    let mut solidity = vec!["Solid".to_string(); count];
+    let mut material = vec!["Normal".to_string(); count];
+    let mut anim = vec!["None".to_string(); count];
+    let mut hit = vec!["None".to_string(); count];
+    let mut hidden = vec![false; count];
+    for line in src.lines() {
+        let line = line.split('#').next().unwrap();
+        let mut words = line.split_whitespace();
+        let range = match words.next() {
+            Some(c) => c,
+            None => continue
+        };
+        let (start, end) = match range.split_once('-') {
+            Some((a, b)) => (a, b),
+            None => (range, range)
+        };
+        let start = usize::from_str_radix(start, 16).unwrap();
+        let end = usize::from_str_radix(end, 16).unwrap();
+        for word in words {
+            for id in start..=end {
+                match word.split_once(':') {
+                    Some(("conveyor", speed)) => material[id] = format!("Conveyor({})", speed.parse::<i32>().unwrap()),
+                    Some(("bouncy", speed)) => material[id] = format!("Bouncy({})", speed.parse::<i32>().unwrap()),
+                    Some(("prize", prize)) => {
+                        let (prize, used) = prize.split_once(':').unwrap();
+                        let prize = match prize {
+                            "coin" => "Coin",
+                            "star" => "Star",
+                            c => panic!("unknown prize `{}`", c)
+                        };
+                        hit[id] = format!("Prize {{ prize: Prize::{}, used: 0x{:X} }}", prize, u16::from_str_radix(used, 16).unwrap());
+                    }
+                    Some(("anim", frames)) => {
+                        let (speed, frames) = frames.split_once(':').unwrap();
+                        let speed = speed.parse::<u16>().unwrap();
+                        assert!(speed > 0, "tile {:X} animates with a speed of 0", id);
+                        let frames: Vec<String> = frames.split(',')
+                            .map(|c| format!("0x{:X}", u16::from_str_radix(c, 16).unwrap()))
+                            .collect();
+                        anim[id] = format!("Some(TileAnim {{ speed: {}, frames: &[{}] }})", speed, frames.join(", "));
+                    }
+                    _ => match word {
+                        "solid" => solidity[id] = "Solid".to_string(),
+                        "nonsolid" => solidity[id] = "NonSolid".to_string(),
+                        "coin" => solidity[id] = "Coin".to_string(),
+                        "water" => solidity[id] = "Water".to_string(),
+                        "climbable" => solidity[id] = "Climbable".to_string(),
+                        "semisolid" => solidity[id] = "Semisolid".to_string(),
+                        "hurt_top" => solidity[id] = "HurtTop".to_string(),
+                        "ice" => material[id] = "Ice".to_string(),
+                        "sticky" => material[id] = "Sticky".to_string(),
+                        "breakable" => hit[id] = "Shatter".to_string(),
+                        "hidden" => hidden[id] = true,
+                        c => panic!("unknown block property `{}`", c)
+                    }
+                }
+            }
+        }
+    }
+    (0..count).map(|id| format!(
+        "TileProps {{ solidity: Solidity::{}, material: Material::{}, anim: {}, hit: BlockHit::{}, hidden: {} }}",
+        solidity[id], material[id], anim[id], hit[id], hidden[id]
+    )).collect()
+}
+
+/// Parses a tileset's land materials into `LandMaterial` expressions.
+fn land_materials(src: &str) -> Vec<String> {
+    let hex_list = |list: &str| -> Vec<String> {
+        list.split(',').map(|c| format!("0x{:X}", u16::from_str_radix(c, 16).unwrap())).collect()
+    };
+    let mut materials = vec![];
+    for line in src.lines() {
+        let line = line.split('#').next().unwrap();
+        let mut words = line.split_whitespace();
+        let block = match words.next() {
+            Some(c) => u16::from_str_radix(c, 16).unwrap(),
+            None => continue
+        };
+        let mut edges = None;
+        let mut corners = "None".to_string();
+        let mut decor = (0, vec![]);
+        for word in words {
+            match word.split_once(':') {
+                Some(("edges", tiles)) => {
+                    let tiles = hex_list(tiles);
+                    assert_eq!(tiles.len(), 16, "land material {:X} needs 16 edges", block);
+                    edges = Some(tiles);
+                }
+                Some(("corners", tiles)) => {
+                    let tiles = hex_list(tiles);
+                    assert_eq!(tiles.len(), 4, "land material {:X} needs 4 corners", block);
+                    corners = format!("Some([{}])", tiles.join(", "));
+                }
+                Some(("decor", decor_def)) => {
+                    let (chance, tiles) = decor_def.split_once(':').unwrap();
+                    decor = (chance.parse::<u8>().unwrap(), hex_list(tiles));
+                }
+                _ => panic!("unknown land property `{}`", word)
+            }
+        }
+        let edges = edges.unwrap_or_else(|| panic!("land material {:X} has no edges", block));
+        materials.push(format!(
+            "LandMaterial {{ block: 0x{:X}, edges: [{}], corners: {}, decor_chance: {}, decor: &[{}] }}",
+            block, edges.join(", "), corners, decor.0, decor.1.join(", ")
+        ));
+    }
+    materials
+}
+
+/// Reads a color written as RRGGBB, into the form the framebuffer has.
+fn color(src: &str) -> u32 {
+    0xFF000000 | (u32::from_str_radix(src, 16).unwrap().swap_bytes() >> 8)
+}
+
+/// Finds a color written as RRGGBB in a palette.
+fn palette_index(src: &str, colors: &[u32]) -> usize {
+    let color = color(src);
+    colors.iter().position(|&c| c == color).unwrap_or_else(|| panic!("color {} isn't in the palette", src))
+}
+
+/// Reads the palette effects of a tileset, the graphics embedded last, as `ColorCycle` expressions.
+fn color_cycles(src: &str, gfx: &DataDef, pal: &[u32]) -> Vec<String> {
+    let mut cycles = vec![];
+    for line in src.lines() {
+        let line = line.split('#').next().unwrap();
+        let word = match line.split_whitespace().next() {
+            Some(c) => c,
+            None => continue
+        };
+        match word.split_once(':') {
+            Some(("cycle", cycle)) => {
+                let (speed, colors) = cycle.split_once(':').unwrap();
+                let colors: Vec<String> = colors.split(',')
+                    .map(|c| (gfx.pal + palette_index(c, &pal[gfx.pal..])).to_string())
+                    .collect();
+                cycles.push(format!("ColorCycle {{ speed: {}, colors: &[{}] }}", speed.parse::<u16>().unwrap(), colors.join(", ")));
+            }
+            _ => panic!("unknown palette effect `{}`", word)
+        }
+    }
+    cycles
+}
+
+/// Reads alternate palettes for the graphics embedded last, adding them to the palette data.
+///
+/// Returns a `DataDef` for each of them, sharing the image of `gfx`.
+fn palette_variants(src: &str, gfx: &DataDef, pal: &mut Vec<u32>) -> Vec<String> {
+    let colors = pal[gfx.pal..].to_vec();
+    let mut variants = vec![];
+    for line in src.lines() {
+        let line = line.split('#').next().unwrap();
+        if line.trim().is_empty() { continue; }
+        let mut variant = colors.clone();
+        for swap in line.split_whitespace() {
+            let (from, to) = swap.split_once('=').unwrap_or_else(|| panic!("unknown color swap `{}`", swap));
+            variant[palette_index(from, &colors)] = color(to);
+        }
+        let def = DataDef { offset: gfx.offset, end: gfx.end, pal: pal.len() };
+        pal.extend(variant);
+        variants.push(format!("{:?}", def));
+    }
+    variants
+}
+
+/// Reads a background theme, embedding the images of its layers.
+///
+/// Returns the sky colors, and the layers and raster effects as `BgLayer` and `RasterEffect` expressions.
+fn background_layers(src: &str, data: &mut Vec<u8>, pal: &mut Vec<u32>) -> ([u32; 2], Vec<String>, Vec<String>) {
+    let pair = |c: &str| {
+        let (x, y) = c.split_once(':').unwrap();
+        (x.parse::<i32>().unwrap(), y.parse::<i32>().unwrap())
+    };
+    let mut sky = None;
+    let mut layers = vec![];
+    let mut effects = vec![];
+    for line in src.lines() {
+        let line = line.split('#').next().unwrap();
+        let mut words = line.split_whitespace();
+        let name = match words.next() {
+            Some(c) => c,
+            None => continue
+        };
+        if let Some(colors) = name.strip_prefix("sky:") {
+            let (top, bottom) = colors.split_once(':').unwrap();
+            sky = Some([color(top), color(bottom)]);
+            continue;
+        }
+        if let Some(effect) = raster_effect(line) {
+            effects.push(effect);
+            continue;
+        }
+        let img = image::open(format!("assets/bg/{}.png", name)).unwrap().into_rgba8();
+        let gfx = embed_bg(&img, data, pal);
+        let mut at = (0, 0);
+        let mut scroll = (256, 256);
+        let mut repeat = "";
+        let mut auto = (0, 0);
+        let mut rows = vec![];
+        for word in words {
+            match word.split_once(':') {
+                Some(("at", c)) => at = pair(c),
+                Some(("scroll", c)) => scroll = pair(c),
+                Some(("repeat", c)) => repeat = c,
+                Some(("auto", c)) => auto = pair(c),
+                Some(("rows", c)) => {
+                    rows = c.split(',').map(|c| {
+                        let (row, scroll) = c.split_once('=').unwrap();
+                        format!("({}, {})", row.parse::<i32>().unwrap(), scroll.parse::<i32>().unwrap())
+                    }).collect();
+                }
+                _ => panic!("unknown background layer property `{}`", word)
+            }
+        }
+        layers.push(format!(
+            "BgLayer {{ gfx: {:?}, width: {}, at: vec2({}, {}), scroll: vec2({}, {}), repeat: vec2({}, {}), auto_scroll: vec2({}, {}), rows: &[{}] }}",
+            gfx, img.width(), at.0, at.1, scroll.0, scroll.1, repeat.contains('x'), repeat.contains('y'), auto.0, auto.1, rows.join(", ")
+        ));
+    }
+    (sky.expect("background has no sky"), layers, effects)
+}
+
+/// Reads a raster effect as a `RasterEffect` expression, if the line is one.
+fn raster_effect(line: &str) -> Option<String> {
+    let mut words = line.split_whitespace();
+    let (kind, params) = words.next()?.split_once(':')?;
+    let params: Vec<&str> = params.split(':').collect();
+    let int = |i: usize| params[i].parse::<i32>().unwrap();
+    let kind = match kind {
+        "offsets" => {
+            let table: Vec<String> = params[0].split(',').map(|c| c.parse::<i8>().unwrap().to_string()).collect();
+            format!("RasterKind::Offsets(&[{}])", table.join(", "))
+        }
+        "wave" => format!("RasterKind::Wave {{ amplitude: {}, period: {}, speed: {} }}", int(0), int(1), int(2)),
+        "gradient" => format!("RasterKind::Gradient {{ color: 0x{:08X}, from: {}, to: {} }}", color(params[0]), int(1), int(2)),
+        "split" => format!("RasterKind::Split {{ scroll: {} }}", int(0)),
+        _ => return None
+    };
+    let mut lines = (0, 179);
+    let mut target = "All";
+    for word in words {
+        match word.split_once(':') {
+            Some(("lines", c)) => {
+                let (first, last) = c.split_once(':').unwrap();
+                lines = (first.parse::<i32>().unwrap(), last.parse::<i32>().unwrap());
+            }
+            Some(("on", "bg")) => target = "Background",
+            Some(("on", "fg")) => target = "Foreground",
+            Some(("on", "all")) => target = "All",
+            _ => panic!("unknown raster effect property `{}`", word)
+        }
+    }
+    Some(format!("RasterEffect {{ kind: {}, lines: ({}, {}), target: RasterTarget::{} }}", kind, lines.0, lines.1, target))
+}
+
+/// Reads the sets of raster effects levels can pick from, as slice expressions.
+///
+/// Set 0 has no effects, the ones in the file come after it.
+/// Reads the texts signs show, separated by blank lines, as byte string literals.
+fn messages(src: &str) -> Vec<String> {
+    let mut messages = vec![vec![]];
+    for line in src.lines().filter(|c| !c.starts_with('#')) {
+        if line.trim().is_empty() {
+            if !messages.last().unwrap().is_empty() { messages.push(vec![]); }
+        } else {
+            messages.last_mut().unwrap().push(line.trim_end());
+        }
+    }
+    messages.retain(|c| !c.is_empty());
+    messages.iter().map(|c| format!("b{:?}", c.join("\n"))).collect()
+}
+
+fn raster_effect_sets(src: &str) -> Vec<String> {
+    let mut sets = vec![vec![]];
+    for line in src.lines() {
+        let line = line.split('#').next().unwrap();
+        if line.trim().is_empty() { continue; }
+        if line.starts_with("set ") {
+            sets.push(vec![]);
+            continue;
+        }
+        assert!(sets.len() > 1, "raster effect `{}` isn't in a set", line.trim());
+        let effect = raster_effect(line).unwrap_or_else(|| panic!("unknown raster effect `{}`", line.trim()));
+        sets.last_mut().unwrap().push(effect);
+    }
+    sets.iter().map(|c| format!("&[{}]", c.join(", "))).collect()
+}
@@ -0,0 +1,35 @@
+//! Times the foreground renderer against the per-pixel one it replaced.
+//!
+//! Run with `cargo run --release --example render_bench`. The wasm build has the
+//! same benchmark, see `www/bench.html`.
+
+use std::hint::black_box;
+use std::time::Instant;
+use tooth_engine::{state, framebuffer::Framebuffer, foreground::Foreground, raster::Scanlines, vec2::{vec2, Vec2}};
+
+const FRAMES: i32 = 2000;
+
+/// Pans diagonally across the whole level, so both empty and busy screens get drawn.
+fn camera(fg: &Foreground, frame: i32) -> Vec2<i32> {
+    let range = fg.size() * 16 - Framebuffer::size();
+    vec2(range.x * frame / FRAMES, range.y * frame / FRAMES)
+}
+
+fn time(name: &str, fg: &Foreground, fb: &mut Framebuffer, render: impl Fn(Vec2<i32>, &mut Framebuffer)) -> f64 {
+    let start = Instant::now();
+    for i in 0..FRAMES {
+        render(camera(fg, i), fb);
+        black_box(&mut *fb);
+    }
+    let ms = start.elapsed().as_secs_f64() * 1000.0 / FRAMES as f64;
+    println!("{:>10}: {:.3} ms/frame", name, ms);
+    ms
+}
+
+fn main() {
+    let fg = &unsafe { state::get() }.unwrap_level().foreground;
+    let mut fb = Box::new(Framebuffer::new());
+    let tiles = time("tiles", fg, &mut fb, |camera, fb| fg.render(camera, &Scanlines::flat(camera), fb));
+    let sampled = time("per pixel", fg, &mut fb, |camera, fb| fg.render_sampled(camera, fb));
+    println!("{:.1}x faster", sampled / tiles);
+}
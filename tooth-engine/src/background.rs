@@ -1,36 +1,129 @@
 use crate::framebuffer::Framebuffer;
 use crate::vec2::{vec2, Vec2};
 use crate::graphics::{self, DataDef};
+use crate::raster::{self, RasterEffect, Scanlines};
+use crate::palette;
+
+/// A background theme, generated from its definition in `assets/bg`.
+pub struct BackgroundDef {
+    /// Sky colors at the top and bottom of the screen.
+    pub sky: [u32; 2],
+    /// Layers of scenery over the sky, back to front.
+    pub layers: &'static [BgLayer],
+    /// Raster effects that come with the theme.
+    pub effects: &'static [RasterEffect],
+}
+
+impl BackgroundDef {
+    /// Color of the sky on a row of the screen.
+    pub fn sky_color(&self, y: i32) -> u32 {
+        let [top, bottom] = self.sky;
+        palette::faded(raster::blend(top, bottom, y * 256 / (Framebuffer::HEIGHT as i32 - 1)))
+    }
+}
+
+/// An image in a background, and how it moves.
+pub struct BgLayer {
+    pub gfx: DataDef,
+    pub width: i32,
+    /// Where the top left of the image is on screen, when the camera is at the top left of the level.
+    pub at: Vec2<i32>,
+    /// How far the layer moves when the camera does, in 1/256ths.
+    pub scroll: Vec2<i32>,
+    /// Whether the image is tiled across the screen on each axis, rather than drawn once.
+    pub repeat: Vec2<bool>,
+    /// How far the layer moves on its own, in 1/256 pixels per frame.
+    pub auto_scroll: Vec2<i32>,
+    /// Horizontal scroll of some rows of the image, in 1/256ths, sorted by row.
+    ///
+    /// Rows in between are blended, and ones past either end scroll like the closest listed row.
+    pub rows: &'static [(i32, i32)],
+}
+
+impl BgLayer {
+    pub fn size(&self) -> Vec2<i32> {
+        vec2(self.width, self.gfx.get_data().len() as i32 / self.width)
+    }
+    /// How far a row of the image moves when the camera does, in 1/256ths.
+    pub fn row_scroll(&self, y: i32) -> i32 {
+        let rows = self.rows;
+        match rows.iter().position(|&(row, _)| row > y) {
+            None => rows.last().map_or(self.scroll.x, |c| c.1),
+            Some(0) => rows[0].1,
+            Some(i) => {
+                let ((a, from), (b, to)) = (rows[i - 1], rows[i]);
+                from + (to - from) * (y - a) / (b - a)
+            }
+        }
+    }
+}
 
 pub struct Background {
-    gfx: DataDef
+    def: &'static BackgroundDef,
+    /// Frame counter for auto-scrolling layers.
+    timer: i32,
 }
 
 impl Background {
-    pub const fn new() -> Background {
+    pub fn new() -> Background {
         Background {
-            gfx: graphics::DUNE_BG
+            def: &graphics::BACKGROUNDS[0],
+            timer: 0,
         }
     }
-    pub fn render(&self, camera: Vec2<i32>, into: &mut Framebuffer) {
-        let data = self.gfx.get_data();
-        let pal = self.gfx.get_pal();
-        let camera = vec2(camera.x / 4, -52);
-        for (pos,i) in into.pixels() {
-            let mut pos = pos + camera;
-            if pos.y >= 64 {
-                pos.x += camera.x * ((pos.y - 58) / 6) / 6;
-            }
-            pos.x %= 320;
-            if pos.y < 0 {
-                *i = 0xff_b5_9f_e6;
+    /// Switches to one of the themes in `graphics::BACKGROUNDS`, falling back to the first one.
+    pub fn set_theme(&mut self, id: u8) {
+        self.def = graphics::BACKGROUNDS.get(id as usize).unwrap_or(&graphics::BACKGROUNDS[0]);
+    }
+    pub fn tick(&mut self) {
+        self.timer = self.timer.wrapping_add(1);
+    }
+    pub fn timer(&self) -> i32 {
+        self.timer
+    }
+    pub fn effects(&self) -> &'static [RasterEffect] {
+        self.def.effects
+    }
+    pub fn render(&self, camera: Vec2<i32>, lines: &Scanlines, into: &mut Framebuffer) {
+        for (y, row) in into.chunks_mut(Framebuffer::WIDTH).enumerate() {
+            let sky = self.def.sky_color(y as i32);
+            for px in row.iter_mut() { *px = sky; }
+        }
+        for layer in self.def.layers {
+            self.draw_layer(layer, camera, lines, into);
+        }
+        for (y, row) in into.chunks_mut(Framebuffer::WIDTH).enumerate() {
+            if lines.tint[y].1 == 0 { continue; }
+            for px in row.iter_mut() { *px = lines.tint(y, *px); }
+        }
+    }
+    /// Draws the opaque pixels of a layer over what's on screen, a row at a time.
+    fn draw_layer(&self, layer: &BgLayer, camera: Vec2<i32>, lines: &Scanlines, into: &mut Framebuffer) {
+        let data = layer.gfx.get_data();
+        let pal = layer.gfx.get_pal();
+        let size = layer.size();
+        let drift = layer.auto_scroll * self.timer / 256;
+        let top = layer.at.y + drift.y - camera.y * layer.scroll.y / 256;
+        for (sy, row) in into.chunks_mut(Framebuffer::WIDTH).enumerate() {
+            let mut y = sy as i32 - top;
+            if layer.repeat.y {
+                y = y.rem_euclid(size.y);
+            } else if y < 0 || y >= size.y {
                 continue;
             }
-            let px = pal[data[(pos.y * 320 + pos.x) as usize] as usize];
-            if px != 0 {
-                *i = px;
+            let left = layer.at.x + drift.x + lines.shift[sy] - lines.camera_x[sy] * layer.row_scroll(y) / 256;
+            // Part of the row the image covers, and where in the image it starts
+            let (start, end, x) = if layer.repeat.x {
+                (0, row.len(), (-left).rem_euclid(size.x) as usize)
             } else {
-                *i = 0xff_b5_9f_e6;
+                let start = left.max(0).min(row.len() as i32);
+                let end = (left + size.x).max(start).min(row.len() as i32);
+                (start as usize, end as usize, (start - left) as usize)
+            };
+            let line = &data[(y * size.x) as usize..((y + 1) * size.x) as usize];
+            for (px, &c) in row[start..end].iter_mut().zip(line.iter().cycle().skip(x)) {
+                let c = pal[c as usize];
+                if c != 0 { *px = c; }
             }
         }
     }
@@ -1,145 +1,243 @@
-use super::{Entity, EntityData};
+use super::{Entity, EntityData, EntityId};
 use crate::framebuffer::Framebuffer;
 use crate::vec2::{vec2, Vec2};
 use crate::foreground::Foreground;
-use crate::foreground::Solidity;
+use crate::foreground::{Solidity, Material, BlockHit};
+use crate::graphics::TileShape;
 use crate::controller::Buttons;
 use crate::state::level::LevelState;
 
+/// Something that happened when an entity touched a tile.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ContactKind {
+    /// Touched a coin.
+    Coin,
+    /// Landed on something harmful.
+    Hurt,
+    /// Got launched by a bouncy surface.
+    Bounce,
+    /// Hit a block that reacts to it from below.
+    Bump,
+    /// Landed hard on a block that reacts to being hit.
+    Stomp,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TileContact {
+    pub kind: ContactKind,
+    /// Position of the tile, in blocks.
+    pub tile: Vec2<i32>,
+}
+
+/// A tile contact, tagged with the entity that made it.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TileEvent {
+    pub source: EntityId,
+    pub contact: TileContact,
+}
+
+/// Tile events collected during a frame, to be handled by the level afterwards.
+pub struct EventQueue {
+    list: [Option<TileEvent>; 64],
+}
+
+impl Default for EventQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventQueue {
+    pub const fn new() -> Self {
+        EventQueue { list: [None; 64] }
+    }
+    /// Moves the contacts made by an entity into the queue.
+    ///
+    /// Events that don't fit in the queue are dropped.
+    pub fn record(&mut self, source: EntityId, data: &mut EntityData) {
+        for contact in data.contacts.iter_mut().filter_map(|c| c.take()) {
+            if let Some(slot) = self.list.iter_mut().find(|c| c.is_none()) {
+                *slot = Some(TileEvent { source, contact });
+            }
+        }
+    }
+    /// Takes all events out of the queue, leaving it empty.
+    pub fn take(&mut self) -> impl Iterator<Item=TileEvent> {
+        let list = core::mem::replace(&mut self.list, [None; 64]);
+        IntoIterator::into_iter(list).flatten()
+    }
+}
+
 impl EntityData {
-    pub fn collide(&mut self, sensor_loc: Vec2<i32>, foreground: &mut Foreground) -> Solidity {
+    /// Remembers that a tile was touched this frame, for the owner to react to.
+    pub fn touch(&mut self, kind: ContactKind, tile: Vec2<i32>) {
+        let contact = Some(TileContact { kind, tile });
+        if self.contacts.contains(&contact) { return; }
+        if let Some(slot) = self.contacts.iter_mut().find(|c| c.is_none()) {
+            *slot = contact;
+        }
+    }
+    pub fn collide(&mut self, sensor_loc: Vec2<i32>, foreground: &Foreground) -> Solidity {
         use Solidity::*;
         let sensor = foreground.solidity_at(sensor_loc / 16);
-        match sensor {
-            Coin => {
-                use crate::state::get;
-                use crate::entity;
-                let state = unsafe { get() }.unwrap_level();
-                state.entity_set.spawn(entity::explosion((sensor_loc / 16 * 16 + vec2(8, 14)) * 256));
-                state.data.coins += 1;
-                let block = foreground.block_at_mut(sensor_loc / 16);
-                *block = 0;
-            },
-            _ => {}
+        if sensor == Coin {
+            self.touch(ContactKind::Coin, sensor_loc / 16);
         }
         sensor
     }
-    pub fn sensor_down(&mut self, sensor_loc: Vec2<i32>, sensor_id: usize, foreground: &mut Foreground) -> Option<i32> {
+    pub fn sensor_down(&mut self, sensor_loc: Vec2<i32>, sensor_id: usize, foreground: &Foreground) -> Option<i32> {
         use Solidity::*;
         let sensor = self.collide(sensor_loc, foreground);
         let block_y = sensor_loc.y * 256 & 0x7FFFF000;
         match sensor {
             Solid => {
-                self.angle = 0;
-                return Some(block_y - 1 * 256);
+                let mut inside_tile = sensor_loc % 16;
+                let mut block_y = block_y;
+                let mut shape = foreground.shape_at(sensor_loc / 16);
+                // Slopes are only stood on with the sensor on their high side
+                if is_slope(shape) && (shape.angle > 0) == (sensor_id != 0) {
+                    return None;
+                }
+                // A full block right under a slope continues its surface
+                if let Some(above) = slope_above(foreground, sensor_loc) {
+                    shape = above;
+                    inside_tile.y += 16;
+                    block_y -= 16 * 256;
+                }
+                match shape.column(inside_tile.x) {
+                    Some((top, _)) if inside_tile.y >= top - 1 || (is_slope(shape) && self.on_ground && self.vel.y >= 0) => {
+                        if self.vel.y >= Self::STOMP_SPEED && foreground.props_at(sensor_loc / 16).hit != BlockHit::None {
+                            self.touch(ContactKind::Stomp, sensor_loc / 16);
+                        }
+                        self.angle = if shape.is_ceiling() { 0 } else { shape.angle as i32 };
+                        return Some(block_y + (top - 1) * 256);
+                    }
+                    _ => {}
+                }
             }
-            EjectUp => {
-                self.angle = 0;
-                return Some(block_y - 1 * 256);
-            },
-            Semisolid if self.pos.y <= block_y => {
+            Semisolid if self.pos.y <= block_y && self.drop_timer == 0 => {
                 self.angle = 0;
                 return Some(block_y - 1 * 256);
             }
             HurtTop => {
+                self.touch(ContactKind::Hurt, sensor_loc / 16);
                 self.vel.y = -2048;
                 return Some(block_y - 1 * 256);
             }
-            Slab if sensor_loc.y % 16 > 7 => {
-                return Some(block_y + 7 * 256);
-            }
-            SlopeAssist { direction: v, steep } /*if if v { self.vel.x < 0 } else { self.vel.x > 0 }*/ => {
-                self.angle = if v { 1 } else { -1 } * if steep { 2 } else { 1 };
-                let px = -2;
-                return Some(block_y + px * 256);
-            }
-            SlopeSteep(v) if v ^ (sensor_id != 0) => {
-                let inside_tile = sensor_loc % 16;
-                if v {
-                    if inside_tile.y >= inside_tile.x - 1 || (self.on_ground && self.vel.y >= 0) {
-                        self.angle = 2;
-                        return Some(block_y + (inside_tile.x - 1) * 256);
-                    }
-                } else {
-                    if inside_tile.y >= 15 - inside_tile.x || (self.on_ground && self.vel.y >= 0) {
-                        self.angle = -2;
-                        return Some(block_y + (15 - inside_tile.x) * 256);
-                    }
-                }
-            }
-            SlopeLow(v) if v ^ (sensor_id != 0) => {
-                let inside_tile = sensor_loc % 16;
-                if v {
-                    if inside_tile.y >= inside_tile.x/2 + 7 || (self.on_ground && self.vel.y >= 0) {
-                        self.angle = 1;
-                        return Some(block_y + (inside_tile.x/2 + 7) * 256);
-                    }
-                } else {
-                    if inside_tile.y >= 14 - inside_tile.x/2 || (self.on_ground && self.vel.y >= 0) {
-                        self.angle = -1;
-                        return Some(block_y + (14 - inside_tile.x/2) * 256);
-                    }
-                }
-            }
-            SlopeHigh(v) if v ^ (sensor_id != 0) => {
-                let inside_tile = sensor_loc % 16;
-                if v {
-                    if inside_tile.y >= inside_tile.x/2 - 1 || (self.on_ground && self.vel.y >= 0) {
-                        self.angle = 1;
-                        return Some(block_y + (inside_tile.x/2 - 1) * 256);
-                    }
-                } else {
-                    if inside_tile.y >= 6 - inside_tile.x/2 || (self.on_ground && self.vel.y >= 0) {
-                        self.angle = -1;
-                        return Some(block_y + (6 - inside_tile.x/2) * 256);
-                    }
-                }
-            }
             _ => {}
         }
         None
     }
-    pub fn sensor_up(&mut self, sensor_loc: Vec2<i32>, _sensor_id: usize, foreground: &mut Foreground) -> Option<i32> {
+    pub fn sensor_up(&mut self, sensor_loc: Vec2<i32>, _sensor_id: usize, foreground: &Foreground) -> Option<i32> {
         use Solidity::*;
         let sensor = self.collide(sensor_loc, foreground);
-        match sensor {
-            Solid | Slab => {
-                return Some((sensor_loc.y * 256 & 0x7FFFF000) + (16 + self.hitbox.y) * 256);
+        let block_y = (sensor_loc.y * 256) & 0x7FFFF000;
+        let props = foreground.props_at(sensor_loc / 16);
+        // Hidden blocks only stop things coming from below them
+        let hidden = props.hidden && self.pos.y - self.hitbox.y * 256 >= block_y + 16 * 256;
+        let res = if hidden {
+            Some(block_y + (16 + self.hitbox.y) * 256)
+        } else if sensor != Solid {
+            None
+        } else {
+            match foreground.shape_at(sensor_loc / 16).column(sensor_loc.x % 16) {
+                Some((top, bottom)) if (top..bottom).contains(&(sensor_loc.y % 16)) => {
+                    Some(block_y + (bottom + self.hitbox.y) * 256)
+                }
+                _ => None
             }
-            _ => {}
+        };
+        if res.is_some() && props.hit != BlockHit::None {
+            self.touch(ContactKind::Bump, sensor_loc / 16);
         }
-        None
+        res
     }
-    pub fn sensor_side(&mut self, sensor_loc: Vec2<i32>, foreground: &mut Foreground, is_right: bool) -> Option<i32> {
+    pub fn sensor_side(&mut self, sensor_loc: Vec2<i32>, foreground: &Foreground, is_right: bool) -> Option<i32> {
         use Solidity::*;
         let sensor = self.collide(sensor_loc, foreground);
         let loc = sensor_loc.x * 256 & 0x7FFFF000;
-        let loc_clamp = if is_right {
-            loc + (-1 - self.hitbox.x / 2) * 256
-        } else {
-            loc + (16 + self.hitbox.x / 2) * 256
-        };
         match sensor {
-            Solid | HurtTop => {
-                return Some(loc_clamp);
-            }
-            Slab if sensor_loc.y % 16 > 7 => {
-                return Some(loc_clamp);
+            HurtTop => {
+                return Some(if is_right {
+                    loc + (-1 - self.hitbox.x / 2) * 256
+                } else {
+                    loc + (16 + self.hitbox.x / 2) * 256
+                });
             }
-            SlopeSteep(v) if v != is_right => {
-                // not sure if this is necessary, but I think this can reduce some jank.
-                self.angle = 2 * if v { 1 } else { -1 };
+            Solid => {
+                let shape = foreground.shape_at(sensor_loc / 16);
+                if is_slope(shape) {
+                    if shape.angle.abs() == 2 && (shape.angle > 0) != is_right {
+                        // not sure if this is necessary, but I think this can reduce some jank.
+                        self.angle = shape.angle as i32;
+                    }
+                    return None;
+                }
+                // Slopes are walked up by the ground sensors instead
+                if slope_above(foreground, sensor_loc).is_some() {
+                    return None;
+                }
+                let inside_tile = sensor_loc % 16;
+                match shape.row(inside_tile.y) {
+                    Some((left, right)) if (left..right).contains(&inside_tile.x) => {
+                        return Some(if is_right {
+                            loc + (left - 1 - self.hitbox.x / 2) * 256
+                        } else {
+                            loc + (right + self.hitbox.x / 2) * 256
+                        });
+                    }
+                    _ => {}
+                }
             }
             _ => {}
         }
         None
     }
     // 14093
-    pub fn process_collision(&mut self, foreground: &mut Foreground) {
+    /// Falling speed needed to hit blocks by landing on them.
+    pub const STOMP_SPEED: i32 = 0x400;
+    /// Largest distance moved in one collision step, in 1/256ths of a pixel.
+    ///
+    /// Anything faster is split into several steps, so it can't pass through thin walls and floors.
+    pub const MAX_STEP: i32 = 7 * 256;
+    pub fn process_collision(&mut self, foreground: &Foreground) {
         self.blocked_by = [false; 4];
+        if self.drop_timer > 0 { self.drop_timer -= 1; }
+        let mut carry = 0;
+        if self.on_ground {
+            match self.ground {
+                Material::Conveyor(speed) => carry = speed,
+                Material::Sticky => self.vel.x = self.vel.x.clamp(-Self::STICKY_MAX_SPEED, Self::STICKY_MAX_SPEED),
+                _ => {}
+            }
+        }
+        let delta = vec2(self.vel.x + carry, self.vel.y);
+        let steps = ((delta.x.abs().max(delta.y.abs()) + Self::MAX_STEP - 1) / Self::MAX_STEP).max(1);
+        let mut ground_sensor = None;
+        for i in 0..steps {
+            // Split the movement evenly, so the steps always add up to the full distance
+            let mut part = delta * (i + 1) / steps - delta * i / steps;
+            if self.blocked_by[2] || self.blocked_by[3] { part.x = 0; }
+            if self.blocked_by[0] || self.blocked_by[1] { part.y = 0; }
+            ground_sensor = self.collision_step(part, foreground).or(ground_sensor);
+        }
+        self.ground = match ground_sensor {
+            Some(sensor) if self.on_ground => foreground.material_at(sensor / 16),
+            _ => Material::Normal
+        };
+        if let (Material::Bouncy(speed), Some(sensor)) = (self.ground, ground_sensor) {
+            self.touch(ContactKind::Bounce, sensor / 16);
+            self.vel.y = -speed;
+            self.on_ground = false;
+        }
+        self.in_water = foreground.is_water(self.pos / 256 - vec2(0, self.hitbox.y / 2));
+    }
+    /// Moves by `delta` and resolves collisions, returning the sensor that landed on the ground, if any.
+    fn collision_step(&mut self, delta: Vec2<i32>, foreground: &Foreground) -> Option<Vec2<i32>> {
         // Apply horizontal momentum
-        let mut next_pos = vec2(self.pos.x + self.vel.x, self.pos.y);
-        let sensor_x_pos = if self.vel.x >= 0 {
+        let mut move_x = delta.x;
+        let mut next_pos = vec2(self.pos.x + move_x, self.pos.y);
+        let sensor_x_pos = if move_x >= 0 {
             self.hitbox.x / 2 + 1
         } else {
             -self.hitbox.x / 2 - 1
@@ -149,7 +247,7 @@ impl EntityData {
             (next_pos / 256 + vec2(sensor_x_pos, -self.hitbox.y / 2)),
             (next_pos / 256 + vec2(sensor_x_pos, 0))
         ];
-        let is_right = self.vel.x >= 0;
+        let is_right = move_x >= 0;
         let mut res = None;
         for (_idx, i) in sensor_locs.iter().enumerate() {
             let l = self.sensor_side(*i, foreground, is_right);
@@ -159,14 +257,15 @@ impl EntityData {
             //self.p_speed = false;
             self.blocked_by[2 + is_right as usize] = true;
             self.vel.x = 0;
+            move_x = 0;
             next_pos.x = c
         }
         self.pos = next_pos;
 
-        let mut next_pos = vec2(self.pos.x, self.pos.y + self.vel.y);
+        let mut next_pos = vec2(self.pos.x, self.pos.y + delta.y);
         if self.on_ground {
             // If on the ground, shift self according to angle
-            next_pos.y += (self.vel.x/2) * self.angle;
+            next_pos.y += (move_x/2) * self.angle;
             if self.angle != 0 { next_pos.y += 256; }  // HACK: this improves sticking to slopes
         }
 
@@ -181,26 +280,49 @@ impl EntityData {
         ];
         let is_down = self.vel.y >= 0;
         let mut res = None;
+        let mut hit_sensor = None;
         for (id, i) in sensor_locs.iter().enumerate() {
             let l = if is_down {
                 self.sensor_down(*i, id, foreground)
             } else {
                 self.sensor_up(*i, id, foreground)
             };
-            res = clamp_opt(l, res, true);
+            if l.is_some() && clamp_opt(l, res, is_down) == l {
+                hit_sensor = Some(*i);
+            }
+            res = clamp_opt(l, res, is_down);
         }
         if let Some(c) = res {
             next_pos.y = c;
-            self.vel.y = 0;
+            // Sloped ceilings turn part of the upwards speed into sideways speed
+            let deflect = match hit_sensor {
+                Some(sensor) if !is_down && foreground.shape_at(sensor / 16).is_ceiling() => -foreground.shape_at(sensor / 16).angle as i32,
+                _ => 0
+            };
+            self.vel.x -= self.vel.y * deflect / 4;
+            self.vel.y = self.vel.y * deflect.abs() / 4;
             self.blocked_by[0 + is_down as usize] = true;
         }
         self.on_ground = self.blocked_by[1];
         self.pos = next_pos;
         self.pos.x = self.pos.x.max(self.hitbox.x / 2 * 256);
+        hit_sensor.filter(|_| is_down)
     }
 }
 
 
+fn is_slope(shape: &TileShape) -> bool {
+    shape.angle != 0 && !shape.is_ceiling()
+}
+
+/// If the sensor is in a full block with a slope on top of it, returns the slope's shape.
+fn slope_above(foreground: &Foreground, sensor_loc: Vec2<i32>) -> Option<&'static TileShape> {
+    let above = sensor_loc / 16 - vec2(0, 1);
+    let is_full = foreground.shape_at(sensor_loc / 16).column(sensor_loc.x % 16) == Some((0, 16));
+    if !is_full || foreground.solidity_at(above) != Solidity::Solid { return None; }
+    Some(foreground.shape_at(above)).filter(|shape| is_slope(shape))
+}
+
 fn clamp_opt<T: Ord>(l: Option<T>, r: Option<T>, is_max: bool) -> Option<T> {
     match (l,r) {
         (None, None) => None,
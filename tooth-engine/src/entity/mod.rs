@@ -1,13 +1,17 @@
 use crate::vec2::{Vec2, vec2};
 
-use crate::foreground::Foreground;
-use crate::framebuffer::Framebuffer;
+use crate::foreground::{Foreground, Material};
+use crate::framebuffer::{Framebuffer, Surface, Transform};
 use crate::controller::Buttons;
 use crate::state::level::LevelState;
+use crate::terrain::{Spawn, SpawnKind, Warp, WarpKind};
+use crate::trig;
 
 mod player;
 mod collision;
 
+pub use collision::{ContactKind, TileContact, TileEvent, EventQueue};
+
 #[derive(Copy, Clone)]
 pub enum EntityKind {
     Key {
@@ -19,6 +23,9 @@ pub enum EntityKind {
     Explosion {
         time_left: i32
     },
+    Splash {
+        time_left: i32
+    },
     Star {
         time_left: i32
     },
@@ -29,6 +36,22 @@ pub enum EntityKind {
     Tomato {
         dead: bool,
         timer: i32
+    },
+    /// An enemy that's been knocked out, spinning as it falls off the screen.
+    Defeated {
+        time_left: i32
+    },
+    /// A piece of a shattered block.
+    Debris {
+        time_left: i32
+    },
+    /// A coin popping out of a block.
+    CoinPop {
+        time_left: i32
+    },
+    /// An invisible door or pipe entrance leading to another area.
+    Warp {
+        warp: Warp
     }
 }
 
@@ -40,8 +63,23 @@ pub struct EntityData {
     pub blocked_by: [bool; 4], // udlr
     pub on_ground: bool,
     pub hflip: bool,
+    pub vflip: bool,
     pub frame: i32,
+    /// Which of the sprite sheet's palettes it's drawn with, see `graphics::MISC_VARIANTS`.
+    pub palette: u8,
+    /// Steepness of the ground being stood on, see `TileShape::angle`.
     pub angle: i32,
+    /// Clockwise rotation of the sprite, in 1/256ths of a turn.
+    pub rotation: i32,
+    /// Size of the sprite, in 1/256ths.
+    pub scale: Vec2<i32>,
+    /// Frames left during which semisolid platforms are ignored.
+    pub drop_timer: i32,
+    pub in_water: bool,
+    /// Material of the ground being stood on.
+    pub ground: Material,
+    /// Tiles touched since the owner last collected them.
+    pub contacts: [Option<TileContact>; 4],
 }
 
 impl EntityData {
@@ -53,8 +91,44 @@ impl EntityData {
             blocked_by: [false; 4],
             on_ground: true,
             hflip: false,
+            vflip: false,
             frame: 0,
+            palette: 0,
             angle: 0,
+            rotation: 0,
+            scale: vec2(256, 256),
+            drop_timer: 0,
+            in_water: false,
+            ground: Material::Normal,
+            contacts: [None; 4],
+        }
+    }
+    /// Maximum horizontal speed when walking through mud.
+    pub const STICKY_MAX_SPEED: i32 = 0x80;
+    /// How the sprite is drawn, from its flips, rotation and scale.
+    pub fn transform(&self) -> Transform {
+        Transform {
+            angle: self.rotation,
+            scale: self.scale,
+            hflip: self.hflip,
+            vflip: self.vflip,
+        }
+    }
+    /// Grows and shrinks the sprite a little, to draw attention to it.
+    pub fn pulse(&mut self, timer: i32) {
+        let size = 256 + trig::sin(timer * 8) / 8;
+        self.scale = vec2(size, size);
+    }
+    /// Accelerates downwards, with weaker gravity and a capped sinking speed underwater.
+    ///
+    /// Underwater, gravity is weakened and capped as much as it is for the player.
+    pub fn fall(&mut self, gravity: i32) {
+        if self.in_water {
+            let params = &player::PhysicsParams::DEFAULT;
+            let gravity = gravity * params.swim_gravity / params.gravity;
+            self.vel.y = (self.vel.y + gravity).min(params.swim_max_sink);
+        } else {
+            self.vel.y += gravity;
         }
     }
 }
@@ -81,6 +155,15 @@ pub fn explosion(pos: Vec2<i32>) -> Entity {
         data
     }
 }
+pub fn splash(pos: Vec2<i32>) -> Entity {
+    let mut data = EntityData::new();
+    data.pos = pos;
+    data.frame = 16;
+    Entity {
+        kind: EntityKind::Splash { time_left: 16 },
+        data
+    }
+}
 pub fn key(pos: Vec2<i32>) -> Entity {
     let mut data = EntityData::new();
     data.pos = pos;
@@ -119,6 +202,75 @@ pub fn tomato(pos: Vec2<i32>) -> Entity {
         data
     }
 }
+pub fn debris(pos: Vec2<i32>, vel: Vec2<i32>) -> Entity {
+    let mut data = EntityData::new();
+    data.pos = pos;
+    data.vel = vel;
+    data.frame = 21;
+    data.hflip = vel.x < 0;
+    Entity {
+        kind: EntityKind::Debris { time_left: 60 },
+        data
+    }
+}
+pub fn coin_pop(pos: Vec2<i32>) -> Entity {
+    let mut data = EntityData::new();
+    data.pos = pos;
+    data.vel = vec2(0, -0x500);
+    data.frame = 20;
+    Entity {
+        kind: EntityKind::CoinPop { time_left: 24 },
+        data
+    }
+}
+
+/// An entity placed in a level.
+pub fn spawn(spawn: &Spawn) -> Entity {
+    let pos = spawn.pos.map(|c| c as i32) * 256;
+    match spawn.kind {
+        SpawnKind::Star => star(pos),
+        SpawnKind::Key => key(pos),
+        SpawnKind::Lock => lock(pos),
+        SpawnKind::Sign => sign(pos, crate::graphics::MESSAGES.get(spawn.arg as usize).copied().unwrap_or(b"")),
+        SpawnKind::Tomato => {
+            let mut tomato = tomato(pos);
+            tomato.data.palette = spawn.arg;
+            tomato
+        }
+    }
+}
+
+/// Where the player starts in an area when not coming out of a warp, standing in `block`.
+pub fn start_point(block: Vec2<u16>) -> Vec2<i32> {
+    (block.map(|c| c as i32) * 16 + vec2(8, 15)) * 256
+}
+
+/// Where the player stands to use a warp, or comes out of it.
+pub fn warp_point(warp: &Warp) -> Vec2<i32> {
+    let block = warp.pos.map(|c| c as i32) * 16;
+    // Pipes are two blocks wide
+    let x = match warp.kind {
+        WarpKind::Door => 8,
+        WarpKind::Pipe => 16,
+    };
+    (block + vec2(x, 15)) * 256
+}
+pub fn warp(warp: Warp) -> Entity {
+    let mut data = EntityData::new();
+    data.pos = warp_point(&warp);
+    data.frame = 0xFF;
+    Entity {
+        kind: EntityKind::Warp { warp },
+        data
+    }
+}
+
+/// Identifies an entity within an `EntitySet`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EntityId {
+    Player,
+    Slot(usize),
+}
 
 #[derive(Copy, Clone)]
 pub struct Entity {
@@ -130,12 +282,13 @@ impl Entity {
         match &mut self.kind {
             EntityKind::Star { ref mut time_left } => {
                 project!(parent.{entity_set, foreground});
-                self.data.vel.y += 0x30;
+                self.data.fall(0x30);
                 if *time_left > 60 || *time_left / 2 % 2 == 0 {
                     self.data.frame = 2;
                 } else {
                     self.data.frame = 0xFF;
                 }
+                self.data.pulse(*time_left);
                 self.data.process_collision(foreground);
                 if self.data.blocked_by[0] {
                     //self.data.vel.y = 4096;
@@ -213,6 +366,14 @@ impl Entity {
                 }
                 done
             }
+            EntityKind::Splash { ref mut time_left } => {
+                let done = *time_left == 0;
+                if !done {
+                    self.data.frame = 19 - (*time_left / 4);
+                    *time_left -= 1;
+                }
+                done
+            }
             EntityKind::Lock => {
                 false
             }
@@ -232,7 +393,7 @@ impl Entity {
             }
             EntityKind::Tomato { ref mut timer, ref mut dead } => {
                 project!(parent.{entity_set, foreground});
-                self.data.vel.y += 0x30;
+                self.data.fall(0x30);
                 self.data.process_collision(foreground);
                 if *dead {
                     self.data.frame = 14;
@@ -248,63 +409,124 @@ impl Entity {
                         self.data.hflip = true;
                     }
                     let delta = (entity_set.player.pos() - self.data.pos) / 256;
-                    if entity_set.player.data_mut().vel.y > self.data.vel.y && delta.x.abs() < 8 && delta.y.abs() < 8 {
-                        *dead = true;
-                        *timer = 40;
-                        self.data.vel.x = 0;
-                        entity_set.player.data_mut().vel.y = -1536;
+                    if delta.x.abs() < 8 && delta.y.abs() < 8 {
+                        if entity_set.player.is_sliding() {
+                            self.knock_out(if delta.x < 0 { 1 } else { -1 });
+                            return false;
+                        } else if entity_set.player.data_mut().vel.y > self.data.vel.y {
+                            *dead = true;
+                            *timer = 40;
+                            self.data.vel.x = 0;
+                            entity_set.player.data_mut().vel.y = -1536;
+                        } else {
+                            entity_set.player.hurt();
+                        }
                     }
                     self.data.frame = 12 + *timer / 8 % 2;
                     *timer += 1;
                     false
                 }
             }
+            EntityKind::Defeated { ref mut time_left } => {
+                self.data.fall(0x40);
+                self.data.pos += self.data.vel;
+                self.data.rotation += if self.data.vel.x < 0 { -12 } else { 12 };
+                *time_left -= 1;
+                *time_left == 0
+            }
+            EntityKind::Debris { ref mut time_left } => {
+                self.data.fall(0x40);
+                self.data.pos += self.data.vel;
+                if *time_left % 4 == 0 { self.data.hflip = !self.data.hflip; }
+                *time_left -= 1;
+                *time_left == 0
+            }
+            EntityKind::CoinPop { ref mut time_left } => {
+                project!(parent.{entity_set});
+                self.data.fall(0x40);
+                self.data.pos += self.data.vel;
+                *time_left -= 1;
+                if *time_left == 0 {
+                    entity_set.spawn(explosion(self.data.pos));
+                }
+                *time_left == 0
+            }
+            EntityKind::Warp { warp } => {
+                project!(parent.{entity_set, buttons});
+                project!(parent.data as level);
+                let player = entity_set.player.data_mut();
+                let delta = (player.pos - self.data.pos) / 256;
+                let entering = match warp.kind {
+                    WarpKind::Door => buttons.up_edge(),
+                    WarpKind::Pipe => buttons.down_edge(),
+                };
+                if entering && player.on_ground && delta.x.abs() < 8 && delta.y.abs() < 4 {
+                    level.start_warp(warp.area, warp.exit);
+                }
+                false
+            }
         }
     }
+    /// Reacts to the block under the entity being bumped from below.
+    pub fn bump(&mut self) {
+        match &mut self.kind {
+            EntityKind::Tomato { dead, .. } if !*dead => self.knock_out(0),
+            EntityKind::Star { .. } => {
+                self.data.vel.y = -0x500;
+            }
+            _ => {}
+        }
+    }
+    /// Sends an enemy spinning off the screen upside down, upwards and `dir` (-1, 0 or 1) sideways.
+    fn knock_out(&mut self, dir: i32) {
+        self.kind = EntityKind::Defeated { time_left: 90 };
+        self.data.vel = vec2(dir * 0x100, -0x400);
+        self.data.vflip = true;
+    }
     pub fn render(&self, camera: Vec2<i32>, into: &mut Framebuffer) {
-        use crate::graphics::MISC;
-        let pal = MISC.get_pal();
-        let data = MISC.get_data();
+        use crate::graphics::{MISC, MISC_VARIANTS};
+        let gfx = MISC_VARIANTS.get(self.data.palette as usize).unwrap_or(&MISC);
+        let pal = gfx.get_pal();
+        let data = gfx.get_data();
 
-        let mut offset = vec2(16,16);
-        offset.x /= 2;
-        let pos = self.data.pos / 256 - camera - offset;
         let frame = self.data.frame as usize;
         if frame == 0xFF { return; }
-        for x in 0..16 {
-            let pos_x = if !self.data.hflip { x } else { 15 - x };
-            for y in 0..16 {
-                if let Some(px) = into.pixel(pos + vec2(x, y + 2)) {
-                    let offset = frame * 256;
-                    let p = pal[data[(pos_x as i32 + y * 16) as usize + offset] as usize];
-                    if p != 0 { *px = p; }
-                }
-            }
-        }
-
+        // Turned and stretched around the middle of the sprite, which sits 6 pixels above `pos`
+        let at = self.data.pos / 256 - camera - vec2(0, 6);
+        into.blit_affine(vec2(16, 16), vec2(8, 8), at, self.data.transform(), |c| {
+            pal[data[frame * 256 + (c.x + c.y * 16) as usize] as usize]
+        });
     }
 }
 
 pub struct EntitySet {
     pub list: [Option<Entity>; 64],
     pub player: player::Player,
+    pub events: EventQueue,
 }
 
 impl EntitySet {
     pub fn new() -> Self {
         EntitySet {
             list: [None; 64],
-            player: player::Player::new()
+            player: player::Player::new(),
+            events: EventQueue::new(),
         }
     }
     pub fn run(&mut self, parent: *mut LevelState) {
-        for c in self.list.iter_mut() {
+        for (idx, c) in self.list.iter_mut().enumerate() {
             if let Some(x) = c {
                 let remove = x.run(parent);
+                self.events.record(EntityId::Slot(idx), &mut x.data);
                 if remove { *c = None; }
             }
         }
         self.player.run(parent);
+        self.events.record(EntityId::Player, self.player.data_mut());
+    }
+    /// Removes every entity but the player.
+    pub fn clear(&mut self) {
+        self.list = [None; 64];
     }
     pub fn spawn(&mut self, entity: Entity) {
         for i in self.list.iter_mut() {
@@ -1,35 +1,149 @@
-use super::{Entity, EntityData};
-use crate::framebuffer::Framebuffer;
+use super::{Entity, EntityData, splash};
+use crate::framebuffer::{Framebuffer, Surface};
 use crate::vec2::{vec2, Vec2};
 use crate::foreground::Foreground;
-use crate::foreground::Solidity;
+use crate::foreground::{Solidity, Material};
 use crate::controller::Buttons;
 use crate::state::level::LevelState;
+use crate::palette;
+
+/// Tunable constants for the player's movement.
+#[derive(Copy, Clone)]
+pub struct PhysicsParams {
+    /// Gravity while airborne.
+    pub gravity: i32,
+    /// Gravity while airborne and holding the jump button.
+    pub gravity_held: i32,
+    /// Maximum falling speed.
+    pub max_fall: i32,
+    /// Gravity while sliding down a wall.
+    pub wall_slide_gravity: i32,
+    /// Maximum falling speed while sliding down a wall.
+    pub wall_slide_max_fall: i32,
+    /// Velocity given by a wall jump, pointing away from the wall.
+    pub wall_jump_speed: Vec2<i32>,
+    /// Frames after a wall jump during which horizontal input is ignored.
+    pub wall_jump_lockout: i32,
+    /// Acceleration when sliding down a slope, per unit of steepness.
+    pub slide_accel: i32,
+    /// Maximum speed when sliding down a slope.
+    pub slide_max_speed: i32,
+    /// Frames during which semisolid platforms are ignored after dropping through one.
+    pub drop_through_time: i32,
+    /// Gravity while underwater.
+    pub swim_gravity: i32,
+    /// Maximum sinking speed underwater.
+    pub swim_max_sink: i32,
+    /// Upwards speed given by each swim stroke.
+    pub swim_stroke: i32,
+    /// Horizontal acceleration underwater.
+    pub swim_accel: i32,
+    /// Maximum horizontal speed underwater.
+    pub swim_max_speed: i32,
+    /// Upwards speed when jumping out of the water.
+    pub water_exit_boost: i32,
+    /// Horizontal acceleration on ice.
+    pub ice_accel: i32,
+    /// Deceleration on ice when not holding a direction.
+    pub ice_friction: i32,
+    /// Speed when climbing ladders and vines.
+    pub climb_speed: i32,
+    /// Upwards speed when jumping off a ladder or vine.
+    pub climb_jump: i32,
+    /// Frames after walking off a ledge during which the player can still jump.
+    pub coyote_time: i32,
+    /// Frames before landing during which a jump press is remembered.
+    pub jump_buffer: i32,
+}
+
+impl PhysicsParams {
+    pub const DEFAULT: Self = PhysicsParams {
+        gravity: 0x60,
+        gravity_held: 0x30,
+        max_fall: 1024,
+        wall_slide_gravity: 0x20,
+        wall_slide_max_fall: 0x140,
+        wall_jump_speed: vec2(0x280, 0x480),
+        wall_jump_lockout: 10,
+        slide_accel: 0x20,
+        slide_max_speed: 0x380,
+        drop_through_time: 8,
+        swim_gravity: 0x10,
+        swim_max_sink: 0x180,
+        swim_stroke: 0x300,
+        swim_accel: 0x0C,
+        swim_max_speed: 0x180,
+        water_exit_boost: 0x600,
+        ice_accel: 0x08,
+        ice_friction: 0x02,
+        climb_speed: 0x100,
+        climb_jump: 0x480,
+        coyote_time: 4,
+        jump_buffer: 4,
+    };
+}
 
 pub struct Player {
     data: EntityData,
+    pub params: PhysicsParams,
     anim_timer: i32,
+    /// Frames left during which the player can still jump after leaving the ground.
     coyote_time: i32,
+    /// Frames left during which a jump press will trigger a jump on landing.
+    jump_buffer: i32,
     p_meter: i32,
     p_speed: bool,
     angle: i32,
+    /// Side of the wall being slid down, if any. `true` is right.
+    wall_slide: Option<bool>,
+    input_lockout: i32,
+    crouching: bool,
+    sliding: bool,
+    climbing: bool,
+    /// Frames left of flashing after getting hurt, during which nothing else can hurt the player.
+    hurt_timer: i32,
     debug_enabled: bool,
     debug_sensors: [Vec2<i32>; 5],
 }
 
 impl Player {
     pub const HITBOX: Vec2<i32> = vec2(10, 24);
+    pub const CROUCH_HITBOX: Vec2<i32> = vec2(10, 14);
     pub const SPRITE_SIZE: Vec2<i32> = vec2(16, 32);
+    /// Number of animation frames in the sprite sheet.
+    pub const FRAMES: usize = 9;
+    pub const WALL_SLIDE_FRAME: i32 = 3;
+    pub const WALL_JUMP_FRAME: i32 = 4;
+    pub const CROUCH_FRAME: i32 = 5;
+    pub const SLIDE_FRAME: i32 = 6;
+    /// First of two alternating climbing frames.
+    pub const CLIMB_FRAME: i32 = 7;
+    /// Rotation of the sprite on ground of each steepness from -2 to 2, in 1/256ths of a turn.
+    ///
+    /// Half as much as the slope itself, so the player still looks like they're standing up.
+    pub const SLOPE_TILT: [i32; 5] = [-16, -9, 0, 9, 16];
+    /// Frames the player flashes for after getting hurt.
+    pub const HURT_TIME: i32 = 90;
+    /// Frames at the start of that during which the screen flashes too.
+    pub const HURT_FLASH_TIME: i32 = 12;
     pub const fn new() -> Self {
         let mut data = EntityData::new();
         data.hitbox = Self::HITBOX;
         Player {
             data,
+            params: PhysicsParams::DEFAULT,
             anim_timer: 0,
-            coyote_time: 4,     // Time when you can still jump off the ground. WIP
+            coyote_time: 0,
+            jump_buffer: 0,
             p_meter: 0,
             p_speed: false,
             angle: 0,
+            wall_slide: None,
+            input_lockout: 0,
+            crouching: false,
+            sliding: false,
+            climbing: false,
+            hurt_timer: 0,
             debug_enabled: false,
             debug_sensors: [vec2(0,0); 5],
         }
@@ -43,8 +157,23 @@ impl Player {
     pub fn data_mut(&mut self) -> &mut EntityData {
         &mut self.data
     }
+    /// Whether the player is sliding down a slope, defeating enemies in the way.
+    pub fn is_sliding(&self) -> bool {
+        self.sliding
+    }
+    /// Starts flashing from getting hurt, unless still flashing from last time.
+    pub fn hurt(&mut self) {
+        if self.hurt_timer == 0 {
+            self.hurt_timer = Self::HURT_TIME;
+        }
+    }
+    /// How much the screen should flash red from the player getting hurt, out of 256.
+    pub fn hurt_flash(&self) -> i32 {
+        (self.hurt_timer - (Self::HURT_TIME - Self::HURT_FLASH_TIME)).max(0) * 128 / Self::HURT_FLASH_TIME
+    }
     pub fn run(&mut self, parent: *mut LevelState) {
         project!(parent.{foreground, buttons, entity_set});
+        if self.hurt_timer > 0 { self.hurt_timer -= 1; }
         let data = &mut self.data;
         if cfg!(feature = "debug") && buttons.start() {
             let speed = if buttons.c() {
@@ -63,20 +192,69 @@ impl Player {
             entity_set.spawn(tomato(data.pos));
         }
         self.debug_enabled ^= buttons.c_edge();
-        if !data.on_ground {
+        let params = &self.params;
+        if self.input_lockout > 0 { self.input_lockout -= 1; }
+        let center = data.pos / 256 - vec2(0, data.hitbox.y / 2);
+        if foreground.solidity_at(center / 16) != Solidity::Climbable {
+            self.climbing = false;
+        } else if buttons.up_edge() || buttons.up() && data.vel.y >= 0 {
+            self.climbing = true;
+        }
+        if self.climbing {
+            self.climb(foreground, buttons);
+            return;
+        }
+        if buttons.a_edge() {
+            self.jump_buffer = params.jump_buffer + 1;
+        }
+        let wants_jump = self.jump_buffer > 0;
+        self.wall_slide = Self::wall_contact(data, foreground, buttons);
+        if let Some(wall_right) = self.wall_slide.filter(|_| buttons.a_edge()) {
+            // Kick off the wall
+            let dir = if wall_right { -1 } else { 1 };
+            data.vel = vec2(params.wall_jump_speed.x * dir, -params.wall_jump_speed.y);
+            data.hflip = wall_right;
+            self.wall_slide = None;
+            self.input_lockout = params.wall_jump_lockout;
+            self.p_speed = false;
+            self.jump_buffer = 0;
+        } else if self.wall_slide.is_some() {
+            data.vel.y += params.wall_slide_gravity;
+            data.vel.y = data.vel.y.min(params.wall_slide_max_fall);
+            self.angle = 0;
+        } else if data.in_water {
+            data.vel.y = (data.vel.y + params.swim_gravity).min(params.swim_max_sink);
+            if buttons.a_edge() {
+                data.vel.y = -params.swim_stroke;
+                data.on_ground = false;
+                self.jump_buffer = 0;
+            }
+            self.sliding = false;
+        } else if !data.on_ground && wants_jump && self.coyote_time > 0 && data.vel.y >= 0 {
+            // Late jump after walking off a ledge
+            Self::jump(data);
+            self.coyote_time = 0;
+            self.jump_buffer = 0;
+        } else if !data.on_ground {
             if buttons.a() {
-                data.vel.y += 0x30;
+                data.vel.y += params.gravity_held;
             } else {
-                data.vel.y += 0x60;
+                data.vel.y += params.gravity;
             }
-            data.vel.y = data.vel.y.min(1024);
+            data.vel.y = data.vel.y.min(params.max_fall);
             self.angle = 0;
         } else {
-            if buttons.a_edge() {
-                let lift = 0x500 + ((data.vel.x.abs() / 0x80) * 10 / 4) * 0x10;
-                data.vel.y = -lift + 0x30;
+            if buttons.down() && buttons.a_edge() && Self::on_semisolid(data, foreground) {
+                // Drop through the platform
+                data.drop_timer = params.drop_through_time;
                 data.on_ground = false;
+                self.sliding = false;
                 self.coyote_time = 0;
+            } else if wants_jump {
+                Self::jump(data);
+                self.coyote_time = 0;
+                self.jump_buffer = 0;
+                self.sliding = false;
             } else {
                 if data.vel.y >= 0 {
                     data.vel.y = 0;
@@ -85,38 +263,78 @@ impl Player {
                 }
             }
         }
-        let max_speed = if self.p_meter == 0x70 { 0x300 } else { 0x240 };
-        if buttons.left() {
+        if data.on_ground {
+            self.coyote_time = params.coyote_time;
+        } else if self.coyote_time > 0 {
+            self.coyote_time -= 1;
+        }
+        if self.jump_buffer > 0 {
+            self.jump_buffer -= 1;
+        }
+        if data.on_ground && buttons.down() && data.angle != 0 {
+            self.sliding = true;
+        } else if self.sliding && data.on_ground && data.angle == 0 && data.vel.x == 0 {
+            self.sliding = false;
+        }
+        let crouch = self.sliding || buttons.down() && (data.on_ground || self.crouching);
+        self.crouching = crouch || self.crouching && !Self::has_headroom(data, foreground);
+        data.hitbox = if self.crouching { Self::CROUCH_HITBOX } else { Self::HITBOX };
+
+        let max_speed = if data.in_water {
+            params.swim_max_speed
+        } else if self.p_meter == 0x70 {
+            0x300
+        } else {
+            0x240
+        };
+        let (accel, turn_accel, friction) = if data.in_water {
+            (params.swim_accel, params.swim_accel * 2, params.swim_accel / 2)
+        } else if data.on_ground && data.ground == Material::Ice {
+            (params.ice_accel, params.ice_accel * 2, params.ice_friction)
+        } else {
+            (0x18, 0x50, 0x10)
+        };
+        let locked = self.input_lockout > 0 || self.crouching && data.on_ground;
+        if self.sliding && data.on_ground && data.angle != 0 {
+            // Slide downhill, faster on steeper slopes
+            let max_speed = params.slide_max_speed;
+            data.vel.x = (data.vel.x + data.angle * params.slide_accel).max(-max_speed).min(max_speed);
+        } else if buttons.left() && !locked {
             data.hflip = true;
             if data.vel.x >= 0 {
-                data.vel.x = (data.vel.x - 0x50).max(-max_speed);
+                data.vel.x = (data.vel.x - turn_accel).max(-max_speed);
             } else {
-                data.vel.x = (data.vel.x - 0x18).max(-max_speed);
+                data.vel.x = (data.vel.x - accel).max(-max_speed);
                 if data.vel.x <= -0x240 && (data.on_ground || self.p_speed) { self.p_meter += 3; }
             }
-        } else if buttons.right() {
+        } else if buttons.right() && !locked {
             data.hflip = false;
             if data.vel.x > 0 {
-                data.vel.x = (data.vel.x + 0x18).min(max_speed);
+                data.vel.x = (data.vel.x + accel).min(max_speed);
                 if data.vel.x >= 0x240 && (data.on_ground || self.p_speed) { self.p_meter += 3; }
             } else {
-                data.vel.x = (data.vel.x + 0x50).min(max_speed);
+                data.vel.x = (data.vel.x + turn_accel).min(max_speed);
             }
-        } else if data.on_ground {
+        } else if data.on_ground || data.in_water {
             // if on ground and not pressing any buttons..
             self.p_speed = false;
             if data.vel.x > 0 {
-                data.vel.x -= 0x10;
+                data.vel.x -= friction;
                 if data.vel.x < 0 { data.vel.x = 0; }
             } else {
-                data.vel.x += 0x10;
+                data.vel.x += friction;
                 if data.vel.x > 0 { data.vel.x = 0; }
             }
         }
         self.p_meter -= 1;
         if self.p_meter > 0x70 { self.p_speed = true; self.p_meter = 0x70; }
         if self.p_meter < 0 { self.p_meter = 0; }
-        if data.on_ground {
+        if self.sliding {
+            data.frame = Self::SLIDE_FRAME;
+            if data.vel.x != 0 { data.hflip = data.vel.x < 0; }
+        } else if self.crouching && data.on_ground {
+            data.frame = Self::CROUCH_FRAME;
+        } else if data.on_ground {
             self.anim_timer += data.vel.x.abs();
             if self.anim_timer > 0xA00 {
                 self.anim_timer -= 0xA00;
@@ -127,32 +345,130 @@ impl Player {
                 self.anim_timer = 0x9FF;
                 data.frame = 0;
             }
+        } else if let Some(wall_right) = self.wall_slide {
+            data.frame = Self::WALL_SLIDE_FRAME;
+            data.hflip = wall_right;
+        } else if self.input_lockout > 0 {
+            data.frame = Self::WALL_JUMP_FRAME;
         } else {
             data.frame = 1;
         }
+        let was_in_water = data.in_water;
         data.process_collision(foreground);
         if data.blocked_by[2] || data.blocked_by[3] { self.p_speed = false; }
+        if data.in_water != was_in_water {
+            entity_set.spawn(splash(data.pos - vec2(0, data.hitbox.y / 2 - 4) * 256));
+            if !data.in_water && data.vel.y < 0 && buttons.a() {
+                // Jump out of the water
+                data.vel.y = -params.water_exit_boost;
+            }
+        }
+    }
+    /// Leaves the ground, jumping higher when running faster.
+    fn jump(data: &mut EntityData) {
+        let lift = 0x500 + ((data.vel.x.abs() / 0x80) * 10 / 4) * 0x10;
+        data.vel.y = -lift + 0x30;
+        data.on_ground = false;
+    }
+    /// Moves around on a ladder or vine, without gravity.
+    fn climb(&mut self, foreground: &mut Foreground, buttons: &Buttons) {
+        let params = &self.params;
+        let data = &mut self.data;
+        self.p_meter = 0;
+        self.p_speed = false;
+        self.sliding = false;
+        self.crouching = false;
+        data.hitbox = Self::HITBOX;
+        if buttons.a_edge() {
+            // Jump off
+            self.climbing = false;
+            data.vel.y = -params.climb_jump;
+            data.vel.x = if buttons.left() { -0x100 } else if buttons.right() { 0x100 } else { 0 };
+            data.frame = 1;
+            data.process_collision(foreground);
+            return;
+        }
+        let dir_x = buttons.right() as i32 - buttons.left() as i32;
+        let dir_y = buttons.down() as i32 - buttons.up() as i32;
+        data.vel = vec2(dir_x, dir_y) * params.climb_speed;
+        if dir_x != 0 { data.hflip = dir_x < 0; }
+        // Don't climb past the top
+        let head = data.pos / 256 - vec2(0, data.hitbox.y / 2) + data.vel / 256;
+        if dir_y < 0 && foreground.solidity_at(head / 16) != Solidity::Climbable {
+            data.vel.y = 0;
+        }
+        if data.vel != vec2(0, 0) {
+            self.anim_timer += 1;
+        }
+        data.frame = Self::CLIMB_FRAME + self.anim_timer / 8 % 2;
+        data.process_collision(foreground);
+        if data.on_ground {
+            self.climbing = false;
+        }
+    }
+    /// Returns the side of the wall the player is sliding down, if any.
+    ///
+    /// Sliding requires falling through the air while pressing into a solid wall.
+    fn wall_contact(data: &EntityData, foreground: &Foreground, buttons: &Buttons) -> Option<bool> {
+        if data.on_ground || data.in_water || data.vel.y < 0 { return None; }
+        let wall_right = if data.blocked_by[3] && buttons.right() {
+            true
+        } else if data.blocked_by[2] && buttons.left() {
+            false
+        } else {
+            return None;
+        };
+        let side = if wall_right { data.hitbox.x / 2 + 1 } else { -data.hitbox.x / 2 - 1 };
+        let sensor = data.pos / 256 + vec2(side, -data.hitbox.y / 2);
+        if foreground.solidity_at(sensor / 16) == Solidity::Solid {
+            Some(wall_right)
+        } else {
+            None
+        }
+    }
+    /// Whether the player is standing on a platform they can drop through.
+    fn on_semisolid(data: &EntityData, foreground: &Foreground) -> bool {
+        let mut found = false;
+        for &side in [-1, 1].iter() {
+            let sensor = data.pos / 256 + vec2(side * data.hitbox.x / 2, 1);
+            match foreground.solidity_at(sensor / 16) {
+                Solidity::Semisolid => found = true,
+                Solidity::NonSolid | Solidity::Coin => {},
+                _ => return false
+            }
+        }
+        found
+    }
+    /// Whether there's enough room above the player to stand up.
+    fn has_headroom(data: &EntityData, foreground: &Foreground) -> bool {
+        [-1, 1].iter().all(|&side| {
+            let sensor = data.pos / 256 + vec2(side * data.hitbox.x / 2, -Self::HITBOX.y);
+            !foreground.is_solid(sensor)
+        })
     }
     pub fn render(&self, camera: Vec2<i32>, into: &mut Framebuffer) {
         use crate::graphics::TOOTHPASTE;
 
-        let pal = TOOTHPASTE.get_pal();
+        let mut pal = TOOTHPASTE.get_pal();
         let data = TOOTHPASTE.get_data();
+        // Flicker while hurt, by drawing with a whitened copy of the palette
+        let flash;
+        if self.hurt_timer / 4 % 2 == 1 {
+            flash = palette::tinted(pal, palette::WHITE, 192);
+            pal = &flash;
+        }
 
-        let mut offset = Self::SPRITE_SIZE;
-        offset.x /= 2;
-        let pos = self.data.pos / 256 - camera - offset;
         let frame = self.data.frame as usize;
-        for x in 0..16 {
-            let pos_x = if !self.data.hflip { x } else { 15 - x };
-            for y in 0..32 {
-                if let Some(px) = into.pixel(pos + vec2(x, y + 2)) {
-                    let offset = if y >= 16 { 3 * 256 + frame * 256 } else { 0 * 256 + frame * 256 };
-                    let p = pal[data[(pos_x as i32 + (y % 16) * 16) as usize + offset] as usize];
-                    if p != 0 { *px = p; }
-                }
-            }
+        // Lean into slopes, around the feet, which are 2 pixels above the bottom of the sprite
+        let mut transform = self.data.transform();
+        if self.data.on_ground {
+            transform.angle = Self::SLOPE_TILT[(self.data.angle.clamp(-2, 2) + 2) as usize];
         }
+        let pivot = vec2(Self::SPRITE_SIZE.x / 2, Self::SPRITE_SIZE.y - 2);
+        into.blit_affine(Self::SPRITE_SIZE, pivot, self.data.pos / 256 - camera, transform, |c| {
+            let offset = if c.y >= 16 { Self::FRAMES + frame } else { frame } * 256;
+            pal[data[(c.x + (c.y % 16) * 16) as usize + offset] as usize]
+        });
 
         if cfg!(feature = "debug") && self.debug_enabled {
             for (pos,i) in into.pixels() {
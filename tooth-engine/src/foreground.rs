@@ -1,105 +1,454 @@
 use crate::framebuffer::Framebuffer;
-use crate::vec2::Vec2;
-use crate::graphics::{self, DataDef};
+use crate::vec2::{vec2, Vec2};
+use crate::graphics::{self, Tileset, TileShape};
+use crate::terrain::BlockMap;
+use crate::raster::Scanlines;
+use crate::palette::{self, ColorCycle};
 
+/// Most blocks an area can have, however they're arranged, counting every layer it uses.
+pub const MAX_BLOCKS: usize = 0x20000;
 
-static mut BLOCKS: [u8; 65536] = [0; 65536];
+const HEIGHT: usize = Framebuffer::HEIGHT;
+
+/// Blocks of every layer, one after another, starting with the main layer.
+static mut BLOCKS: [u16; MAX_BLOCKS] = [0; MAX_BLOCKS];
+
+/// One of the block layers making up the foreground.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LayerId {
+    /// Decoration behind the main layer.
+    Back,
+    /// The layer entities collide with.
+    Main,
+    /// Decoration drawn over entities.
+    Front,
+}
+
+impl LayerId {
+    pub fn from_u8(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(LayerId::Back),
+            1 => Some(LayerId::Main),
+            2 => Some(LayerId::Front),
+            _ => None
+        }
+    }
+}
+
+pub struct Layer {
+    /// Where the layer's blocks start in `Foreground::blocks`, if it has any.
+    start: Option<usize>,
+    /// How far the layer moves when the camera does, in 1/256ths.
+    ///
+    /// The main layer should stay at 256, or it won't line up with collision.
+    pub scroll: Vec2<i32>,
+    pub visible: bool,
+}
 
 pub struct Foreground {
-    blocks: &'static mut [u8; 65536],
-    gfx: DataDef
+    /// Blocks of the layers, see `BLOCKS`.
+    blocks: &'static mut [u16; MAX_BLOCKS],
+    layers: [Layer; 3],
+    /// Size of the level, in blocks.
+    size: Vec2<i32>,
+    tileset: &'static Tileset,
+    /// Everything below this height (in pixels) is underwater.
+    pub water_level: Option<i32>,
+    /// Frame counter for animated tiles.
+    timer: i32,
+    /// Blocks bouncing after being bumped, with the frames left.
+    bumps: [Option<(Vec2<i32>, i32)>; 4],
 }
 
 impl Foreground {
     pub fn new() -> Foreground {
+        let layer = |start, visible| Layer { start, scroll: vec2(256, 256), visible };
         Foreground {
-            blocks: unsafe { &mut BLOCKS },
-            gfx: graphics::DUNE_FG
+            blocks: unsafe { &mut *core::ptr::addr_of_mut!(BLOCKS) },
+            layers: [layer(None, false), layer(Some(0), true), layer(None, false)],
+            size: vec2(256, 128),
+            tileset: &graphics::TILESETS[0],
+            water_level: None,
+            timer: 0,
+            bumps: [None; 4],
         }
     }
-    fn fg_block(&self, tile: usize) -> &[u8] {
-        let data = self.gfx.get_data();
-        &data[tile * 256 .. tile * 256 + 256]
+    /// Switches to one of the tilesets in `graphics::TILESETS`, falling back to the first one.
+    pub fn set_tileset(&mut self, id: u8) {
+        self.tileset = graphics::TILESETS.get(id as usize).unwrap_or(&graphics::TILESETS[0]);
     }
-    pub fn blocks_mut(&mut self) -> &mut [u8] {
-        self.blocks
+    /// Colors of the tileset that rotate, see `palette::update`.
+    pub fn color_cycles(&self) -> &'static [ColorCycle] {
+        self.tileset.cycles
     }
-    pub fn block_at_mut(&mut self, mut at: Vec2<i32>) -> &mut u8 {
-        at.x = at.x.max(0).min(255);
-        at.y = at.y.max(0).min(255);
-        &mut self.blocks[(at.y * 256 + at.x) as usize]
+    pub fn tick(&mut self) {
+        self.timer = self.timer.wrapping_add(1);
+        for bump in self.bumps.iter_mut() {
+            if let Some((_, time)) = bump {
+                *time -= 1;
+                if *time == 0 { *bump = None; }
+            }
+        }
     }
-    pub fn block_at(&self, mut at: Vec2<i32>) -> u8 {
-        at.x = at.x.max(0).min(255);
-        at.y = at.y.max(0).min(255);
-        self.blocks[(at.y * 256 + at.x) as usize]
+    /// How far a bumped block is raised, by frames left.
+    const BUMP_HEIGHT: [i32; 10] = [0, 1, 2, 3, 4, 5, 5, 4, 3, 2];
+    /// Makes a block of the main layer bounce up for a moment.
+    ///
+    /// Only the graphics move, collision stays where it is.
+    pub fn bump(&mut self, at: Vec2<i32>) {
+        let time = Self::BUMP_HEIGHT.len() as i32 - 1;
+        let slot = self.bumps.iter().position(|c| matches!(c, Some((tile, _)) if *tile == at))
+            .or_else(|| self.bumps.iter().position(|c| c.is_none()));
+        if let Some(i) = slot {
+            self.bumps[i] = Some((at, time));
+        }
     }
-    pub fn solidity_at(&self, at: Vec2<i32>) -> Solidity {
-        use Solidity::*;
-        let c = self.block_at(at);
-        match c {
-            0x00 => NonSolid,
-            0x02 => Coin,
-            0x04 => Semisolid,
-            0x05 => HurtTop,
-            0x06 => Semisolid,
-            0x49 => Slab,
-            0x52 => EjectUp,
-            0x46 => SlopeSteep(false),
-            0x47 => SlopeSteep(true),
-            0x56 => SlopeAssist { direction: false, steep: true  },
-            0x57 => SlopeAssist { direction: true,  steep: true  },
-            0x66 => SlopeAssist { direction: false, steep: false },
-            0x74 => SlopeAssist { direction: true,  steep: false },
-            0x64 => SlopeLow(false),
-            0x65 => SlopeHigh(false),
-            0x75 => SlopeHigh(true),
-            0x76 => SlopeLow(true),
-            0x5D ..= 0x5F => Semisolid,
-            0x6D ..= 0x6F => NonSolid,
-            0x80 ..= 0xFF => NonSolid,
-            _ => Solid
+    pub fn is_bumping(&self, at: Vec2<i32>) -> bool {
+        self.bumps.iter().flatten().any(|&(tile, _)| tile == at)
+    }
+    fn fg_block(&self, tile: usize) -> Option<&[u8]> {
+        let data = self.tileset.gfx.get_data();
+        data.get(tile * 256 .. tile * 256 + 256)
+    }
+    pub fn size(&self) -> Vec2<i32> {
+        self.size
+    }
+    pub fn layer(&self, id: LayerId) -> &Layer {
+        &self.layers[id as usize]
+    }
+    pub fn layer_mut(&mut self, id: LayerId) -> &mut Layer {
+        &mut self.layers[id as usize]
+    }
+    /// Resizes the level and clears its main layer. The back and front layers are left without blocks.
+    ///
+    /// Sizes from `LevelHeader::decode` always fit. Others have their height cut down if needed,
+    /// so there are no more than `MAX_BLOCKS` blocks.
+    pub fn set_size(&mut self, size: Vec2<i32>) {
+        let width = size.x.max(1).min(MAX_BLOCKS as i32);
+        self.size = vec2(width, size.y.max(1).min(MAX_BLOCKS as i32 / width));
+        for (i, layer) in self.layers.iter_mut().enumerate() {
+            layer.start = if i == LayerId::Main as usize { Some(0) } else { None };
+        }
+        let len = self.layer_len();
+        self.blocks[..len].fill(0);
+    }
+    /// Blocks in each layer.
+    fn layer_len(&self) -> usize {
+        (self.size.x * self.size.y) as usize
+    }
+    /// Blocks of a layer, or none if it hasn't been given any.
+    fn layer_blocks(&self, id: LayerId) -> &[u16] {
+        let len = self.layer_len();
+        self.layers[id as usize].start.map_or(&[], |start| &self.blocks[start..start + len])
+    }
+    /// Blocks of the main layer.
+    pub fn blocks_mut(&mut self) -> &mut [u16] {
+        let len = self.layer_len();
+        &mut self.blocks[..len]
+    }
+    /// Blocks of the main layer, in the form the `terrain` decoders write to.
+    pub fn block_map(&mut self) -> BlockMap<'_> {
+        let width = self.size.x as usize;
+        BlockMap::new(self.blocks_mut(), width)
+    }
+    /// Blocks of a layer, in the form the `terrain` decoders write to.
+    ///
+    /// The back and front layers get their blocks the first time they're asked for after `set_size`,
+    /// cleared, from what's left of `MAX_BLOCKS`. `None` if there isn't enough left.
+    pub fn layer_map(&mut self, id: LayerId) -> Option<BlockMap<'_>> {
+        let width = self.size.x as usize;
+        let len = self.layer_len();
+        let start = match self.layers[id as usize].start {
+            Some(start) => start,
+            None => {
+                let start = self.layers.iter().filter(|c| c.start.is_some()).count() * len;
+                if start + len > MAX_BLOCKS { return None; }
+                self.blocks[start..start + len].fill(0);
+                self.layers[id as usize].start = Some(start);
+                start
+            }
+        };
+        Some(BlockMap::new(&mut self.blocks[start..start + len], width))
+    }
+    pub fn block_at_mut(&mut self, mut at: Vec2<i32>) -> &mut u16 {
+        at.x = at.x.max(0).min(self.size.x - 1);
+        at.y = at.y.max(0).min(self.size.y - 1);
+        &mut self.blocks[(at.y * self.size.x + at.x) as usize]
+    }
+    pub fn block_at(&self, mut at: Vec2<i32>) -> u16 {
+        at.x = at.x.max(0).min(self.size.x - 1);
+        at.y = at.y.max(0).min(self.size.y - 1);
+        self.blocks[(at.y * self.size.x + at.x) as usize]
+    }
+    /// Properties of the block, as listed in the tileset.
+    pub fn props_at(&self, at: Vec2<i32>) -> &'static TileProps {
+        let props = self.tileset.props;
+        props.get(self.block_at(at) as usize).unwrap_or(&props[0])
+    }
+    /// Which tile of the tileset a block id is drawn with on the current frame.
+    pub fn block_graphic(&self, block: u16) -> u16 {
+        let props = self.tileset.props;
+        match &props.get(block as usize).unwrap_or(&props[0]).anim {
+            Some(anim) => anim.frames[(self.timer as u32 / anim.speed as u32) as usize % anim.frames.len()],
+            None => block
         }
-        //if c == 0 { NonSolid } else { Solid }
+    }
+    pub fn solidity_at(&self, at: Vec2<i32>) -> Solidity {
+        self.props_at(at).solidity
+    }
+    /// Collision shape of the block, used by anything `Solid`.
+    pub fn shape_at(&self, at: Vec2<i32>) -> &'static TileShape {
+        let shapes = self.tileset.shapes;
+        shapes.get(self.block_at(at) as usize).unwrap_or(&shapes[0])
+    }
+    /// Whether the pixel at this position is part of a solid block.
+    pub fn is_solid(&self, pos: Vec2<i32>) -> bool {
+        self.solidity_at(pos / 16) == Solidity::Solid && self.shape_at(pos / 16).is_solid(pos)
+    }
+    pub fn material_at(&self, at: Vec2<i32>) -> Material {
+        self.props_at(at).material
     }
     pub fn sample_pixel(&self, pos: Vec2<i32>) -> Option<u32> {
-        use crate::graphics;
-        let pal = graphics::DUNE_FG.get_pal();
+        self.sample_layer(LayerId::Main, pos)
+    }
+    /// Returns the color at a position on a layer, ignoring its scrolling.
+    pub fn sample_layer(&self, id: LayerId, pos: Vec2<i32>) -> Option<u32> {
+        let pal = self.tileset.gfx.get_pal();
 
         let block_pos = pos.map(|c| c >> 4);
         let block_offset = pos & 15;
         let offset_addr = (block_offset.x + block_offset.y * 16) as usize;
-        let block_addr = (block_pos.x + block_pos.y * 256) as usize;
-        let block_id = *self.blocks.get(block_addr)?;
+        if block_pos.x < 0 || block_pos.x >= self.size.x || block_pos.y < 0 || block_pos.y >= self.size.y {
+            return None;
+        }
+        let block_id = *self.layer_blocks(id).get((block_pos.x + block_pos.y * self.size.x) as usize)?;
         //let block_id = self.block_at(block_pos);
+        let tile = self.block_graphic(block_id);
 
-        pal.get(*self.fg_block(block_id as usize).get(offset_addr)? as usize).copied()
+        pal.get(*self.fg_block(tile as usize)?.get(offset_addr)? as usize).copied()
+    }
+    /// Returns the color of a layer at a pixel on screen, if it's visible there.
+    fn layer_pixel(&self, id: LayerId, pos: Vec2<i32>, camera: Vec2<i32>) -> Option<u32> {
+        let layer = self.layer(id);
+        if !layer.visible { return None; }
+        let pos = pos + camera * layer.scroll / 256;
+        if id == LayerId::Main {
+            for &(tile, time) in self.bumps.iter().flatten() {
+                // Draw the block raised, leaving a gap below it
+                let inside = pos - tile * 16 + vec2(0, Self::BUMP_HEIGHT[time as usize]);
+                if inside.x < 0 || inside.x >= 16 || inside.y < 0 || pos.y >= tile.y * 16 + 16 { continue; }
+                if inside.y < 16 {
+                    let px = self.sample_layer(id, tile * 16 + inside).filter(|&c| c != 0);
+                    if px.is_some() { return px; }
+                }
+                if pos.y >= tile.y * 16 { return None; }
+            }
+        }
+        self.sample_layer(id, pos).filter(|&c| c != 0)
     }
-    pub fn render(&self, camera: Vec2<i32>, into: &mut Framebuffer) {
-        // TODO: copy rows of 16 pixels at a time?
+    /// Whether the pixel at `pos` is underwater.
+    pub fn is_water(&self, pos: Vec2<i32>) -> bool {
+        matches!(self.water_level, Some(c) if pos.y >= c) || self.solidity_at(pos / 16) == Solidity::Water
+    }
+    /// Returns how far below the water surface the pixel at `pos` is.
+    fn water_depth(&self, pos: Vec2<i32>) -> Option<i32> {
+        if let Some(level) = self.water_level {
+            if pos.y >= level { return Some(pos.y - level); }
+        }
+        let block = pos.map(|c| c >> 4);
+        if self.solidity_at(block) != Solidity::Water {
+            None
+        } else if self.solidity_at(block - vec2(0, 1)) == Solidity::Water {
+            Some(16 + (pos.y & 15))
+        } else {
+            Some(pos.y & 15)
+        }
+    }
+    /// Tiles on screen when the lines of a layer start at `left` horizontally, and its top is at `top`.
+    ///
+    /// These can be outside of the level.
+    fn visible_tiles(left: &[i32; HEIGHT], top: i32) -> (Vec2<i32>, Vec2<i32>) {
+        let min = left.iter().copied().min().unwrap_or(0);
+        let max = left.iter().copied().max().unwrap_or(0);
+        (vec2(min, top) >> 4, (vec2(max, top) + Framebuffer::size() - 1) >> 4)
+    }
+    /// Draws the visible blocks of a layer, a row of 16 pixels at a time.
+    fn draw_layer(&self, id: LayerId, camera: Vec2<i32>, lines: &Scanlines, into: &mut Framebuffer) {
+        let layer = self.layer(id);
+        let blocks = self.layer_blocks(id);
+        if !layer.visible || blocks.is_empty() { return; }
+        let pal = self.tileset.gfx.get_pal();
+        let left = line_starts(lines, layer.scroll.x);
+        let top = camera.y * layer.scroll.y / 256;
+        let (first, last) = Self::visible_tiles(&left, top);
+        let first = first.zip(vec2(0, 0), i32::max);
+        let last = last.zip(self.size - 1, i32::min);
+        // Going top to bottom, so bumped blocks end up over the one above them
+        for y in first.y..=last.y {
+            for x in first.x..=last.x {
+                let block = blocks[(x + y * self.size.x) as usize];
+                if block == 0 { continue; }
+                let gfx = match self.fg_block(self.block_graphic(block) as usize) {
+                    Some(c) => c,
+                    None => continue
+                };
+                let tile = vec2(x, y);
+                let mut screen_y = y * 16 - top;
+                if id == LayerId::Main {
+                    // Draw the block raised, leaving a gap below it
+                    if let Some(&(_, time)) = self.bumps.iter().flatten().find(|(c, _)| *c == tile) {
+                        screen_y -= Self::BUMP_HEIGHT[time as usize];
+                    }
+                }
+                blit_tile(into, gfx, pal, vec2(x * 16, screen_y), &left, lines);
+            }
+        }
+    }
+    /// Tints whatever is underwater, skipping tiles that can't be.
+    fn draw_water(&self, camera: Vec2<i32>, lines: &Scanlines, into: &mut Framebuffer) {
+        let left = line_starts(lines, 256);
+        // The water level goes past the edges of the level, so this covers the whole screen
+        let (first, last) = Self::visible_tiles(&left, camera.y);
+        for tile in first.product_range(last + 1) {
+            let below_level = matches!(self.water_level, Some(c) if tile.y * 16 + 15 >= c);
+            if !below_level && self.solidity_at(tile) != Solidity::Water { continue; }
+            for inside in vec2(0, 0).product_range(vec2(16, 16)) {
+                let pos = tile * 16 + inside;
+                let screen_y = pos.y - camera.y;
+                if screen_y < 0 || screen_y >= HEIGHT as i32 { continue; }
+                let screen_x = pos.x - left[screen_y as usize];
+                if screen_x < 0 || screen_x >= Framebuffer::WIDTH as i32 { continue; }
+                let px = &mut into[screen_x as usize + screen_y as usize * Framebuffer::WIDTH];
+                if let Some(depth) = self.water_depth(pos) {
+                    *px = water_tint(*px, pos, depth, self.timer);
+                }
+            }
+        }
+    }
+    pub fn render(&self, camera: Vec2<i32>, lines: &Scanlines, into: &mut Framebuffer) {
+        self.draw_layer(LayerId::Back, camera, lines, into);
+        self.draw_layer(LayerId::Main, camera, lines, into);
+        self.draw_water(camera, lines, into);
+    }
+    /// Draws the front layer, which goes over entities.
+    pub fn render_front(&self, camera: Vec2<i32>, lines: &Scanlines, into: &mut Framebuffer) {
+        self.draw_layer(LayerId::Front, camera, lines, into);
+    }
+    /// Does the same as `render`, but looks up every pixel on its own.
+    ///
+    /// Much slower, it's kept to check the output of `render` against, and for benchmarks.
+    pub fn render_sampled(&self, camera: Vec2<i32>, into: &mut Framebuffer) {
         for (pos,i) in into.pixels() {
+            if let Some(px) = self.layer_pixel(LayerId::Back, pos, camera) { *i = px; }
+            if let Some(px) = self.layer_pixel(LayerId::Main, pos, camera) { *i = px; }
             let pos = pos + camera;
-            let px = self.sample_pixel(pos).unwrap();
-            if px != 0 { *i = px; }
+            if let Some(depth) = self.water_depth(pos) {
+                *i = water_tint(*i, pos, depth, self.timer);
+            }
+        }
+    }
+}
+
+/// Where each line of the screen starts in a layer, horizontally, after scrolling by `scroll` 256ths.
+fn line_starts(lines: &Scanlines, scroll: i32) -> [i32; HEIGHT] {
+    let mut left = [0; HEIGHT];
+    for (y, c) in left.iter_mut().enumerate() {
+        *c = lines.camera_x[y] * scroll / 256 - lines.shift[y];
+    }
+    left
+}
+
+/// Copies the opaque pixels of a tile to the screen, clipped to its edges.
+///
+/// `pos` is where the tile is in its layer horizontally, and on screen vertically.
+fn blit_tile(into: &mut Framebuffer, gfx: &[u8], pal: &[u32], pos: Vec2<i32>, left: &[i32; HEIGHT], lines: &Scanlines) {
+    for y in pos.y.max(0)..(pos.y + 16).min(HEIGHT as i32) {
+        let screen_x = pos.x - left[y as usize];
+        let start = (-screen_x).max(0);
+        let end = (Framebuffer::WIDTH as i32 - screen_x).min(16);
+        if start >= end { continue; }
+        let row = (y - pos.y) * 16;
+        let src = &gfx[(row + start) as usize..(row + end) as usize];
+        let at = y as usize * Framebuffer::WIDTH + (screen_x + start) as usize;
+        let dst = &mut into[at..at + src.len()];
+        for (px, &c) in dst.iter_mut().zip(src) {
+            let c = pal[c as usize];
+            if c != 0 { *px = lines.tint(y as usize, c); }
         }
     }
 }
 
+/// Shades a pixel that's `depth` pixels underwater, with a wavy surface line.
+fn water_tint(px: u32, pos: Vec2<i32>, depth: i32, timer: i32) -> u32 {
+    const WAVE: [i32; 8] = [0, 1, 2, 2, 2, 1, 0, 0];
+    let wave = WAVE[(((pos.x + timer / 4) / 4) & 7) as usize];
+    if depth < wave {
+        px
+    } else if depth == wave {
+        palette::faded(0xFF_FF_F0_E0)
+    } else {
+        // 50% blend with the water color
+        0xFF000000 | (((px >> 1) & 0x7F7F7F) + ((palette::faded(0xFF_D0_80_30) >> 1) & 0x7F7F7F))
+    }
+}
+
 #[derive(Copy,Clone,PartialEq,Eq)]
 pub enum Solidity {
     NonSolid,
     Solid,
     Coin,
+    Water,
+    Climbable,
     Semisolid,
-    EjectUp,
     HurtTop,
-    Slab,
-    SlopeHigh(bool),
-    SlopeLow(bool),
-    SlopeSteep(bool),
-    SlopeAssist {
-        direction: bool,
-        steep: bool
-    },
+}
+
+/// Everything the engine needs to know about a block id, generated from the tileset's properties file.
+pub struct TileProps {
+    pub solidity: Solidity,
+    pub material: Material,
+    /// Graphics the block cycles through, if it's animated.
+    pub anim: Option<TileAnim>,
+    /// What happens when the block is bumped from below.
+    pub hit: BlockHit,
+    /// Whether the block stays invisible and lets everything through, until bumped from below.
+    pub hidden: bool,
+}
+
+/// How a block reacts to being bumped from below.
+#[derive(Copy,Clone,PartialEq,Eq)]
+pub enum BlockHit {
+    None,
+    /// Gives out a prize, then turns into the `used` block.
+    Prize { prize: Prize, used: u16 },
+    /// Breaks into pieces.
+    Shatter,
+}
+
+#[derive(Copy,Clone,PartialEq,Eq)]
+pub enum Prize {
+    Coin,
+    Star,
+}
+
+/// A list of tiles a block is drawn with in turn, in place of its own.
+pub struct TileAnim {
+    /// How many frames each tile is shown for.
+    pub speed: u16,
+    pub frames: &'static [u16],
+}
+
+/// Surface properties of a block, independent of its shape.
+#[derive(Copy,Clone,PartialEq,Eq)]
+pub enum Material {
+    Normal,
+    /// Slippery ground with little friction.
+    Ice,
+    /// Moves anything standing on it by this speed.
+    Conveyor(i32),
+    /// Launches anything landing on it upwards with this speed.
+    Bouncy(i32),
+    /// Mud that slows down anything walking through it.
+    Sticky,
 }
 
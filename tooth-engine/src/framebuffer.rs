@@ -1,5 +1,6 @@
 use core::ops;
 use crate::vec2::{vec2, Vec2};
+use crate::trig;
 
 pub struct Framebuffer {
     inner: [u32; Self::WIDTH * Self::HEIGHT]
@@ -48,8 +49,64 @@ impl ops::DerefMut for Framebuffer {
     }
 }
 
+/// How an image is turned, stretched and mirrored when drawn with `Surface::blit_affine`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Transform {
+    /// Clockwise rotation, in 1/256ths of a turn.
+    pub angle: i32,
+    /// Size on each axis, in 1/256ths.
+    pub scale: Vec2<i32>,
+    /// Whether the image is mirrored left to right, within its own bounds.
+    pub hflip: bool,
+    /// Whether the image is mirrored top to bottom, within its own bounds.
+    pub vflip: bool,
+}
+
+impl Transform {
+    pub const IDENTITY: Transform = Transform {
+        angle: 0,
+        scale: vec2(256, 256),
+        hflip: false,
+        vflip: false,
+    };
+}
+
 pub trait Surface {
     fn pixel(&mut self, position: Vec2<i32>) -> Option<&mut u32>;
+    /// Draws an image turned and stretched around `pivot`, a point in it which ends up at `at`.
+    ///
+    /// `sample` gives the color of each pixel of the image, 0 being transparent. Every pixel drawn
+    /// takes the color of the one it lands nearest to in the image, so the pixels stay sharp, and
+    /// everything is worked out in integers so it draws the same everywhere.
+    fn blit_affine<F: Fn(Vec2<i32>) -> u32>(&mut self, size: Vec2<i32>, pivot: Vec2<i32>, at: Vec2<i32>, transform: Transform, sample: F) {
+        let scale = transform.scale;
+        if scale.x == 0 || scale.y == 0 { return; }
+        let (sin, cos) = (trig::sin(transform.angle), trig::cos(transform.angle));
+        let rotate = |c: Vec2<i32>| vec2(c.x * cos - c.y * sin, c.x * sin + c.y * cos) >> 8;
+        // Part of the screen the corners of the image end up around
+        let mut start = at;
+        let mut end = at;
+        for &corner in [vec2(0, 0), vec2(size.x, 0), vec2(0, size.y), size].iter() {
+            let c = at + rotate(((corner - pivot) * scale) >> 8);
+            start = start.zip(c, i32::min);
+            end = end.zip(c, i32::max);
+        }
+        for pos in (start - 1).product_range(end + 2) {
+            // Middle of the pixel, relative to the pivot, in 1/256 pixels
+            let d = (pos - at) * 256 + 128;
+            // Undo the rotation, then the stretching
+            let r = vec2(d.x * cos + d.y * sin, d.y * cos - d.x * sin) >> 8;
+            let src = (r.zip(scale, |c, s| (c << 8).div_euclid(s)) >> 8) + pivot;
+            if src.x < 0 || src.y < 0 || src.x >= size.x || src.y >= size.y { continue; }
+            let src = vec2(
+                if transform.hflip { size.x - 1 - src.x } else { src.x },
+                if transform.vflip { size.y - 1 - src.y } else { src.y },
+            );
+            let color = sample(src);
+            if color == 0 { continue; }
+            if let Some(px) = self.pixel(pos) { *px = color; }
+        }
+    }
 }
 
 impl Surface for Framebuffer {
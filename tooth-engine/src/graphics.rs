@@ -1,6 +1,11 @@
 use crate::lz4;
 use crate::vec2::{vec2, Vec2};
 use crate::framebuffer::Surface;
+use crate::foreground::{TileProps, TileAnim, BlockHit, Prize, Solidity, Material};
+use crate::terrain::LandMaterial;
+use crate::background::{BackgroundDef, BgLayer};
+use crate::raster::{RasterEffect, RasterKind, RasterTarget};
+use crate::palette::{self, ColorCycle};
 
 pub struct DataDef {
     pub offset: usize,
@@ -12,13 +17,62 @@ impl DataDef {
     pub fn get_data(&self) -> &'static [u8] {
         unsafe { &GFX_DATA[self.offset..self.end] }
     }
+    /// The graphics' colors as they are this frame, see `palette`.
     pub fn get_pal(&self) -> &'static [u32] {
-        &PAL_DATA[self.pal..]
+        unsafe { &PALETTE[self.pal..] }
+    }
+}
+
+/// A set of foreground blocks, with everything needed to draw and collide with them.
+pub struct Tileset {
+    pub gfx: DataDef,
+    pub shapes: &'static [TileShape],
+    pub props: &'static [TileProps],
+    /// Kinds of land, and how they're shaped.
+    pub land: &'static [LandMaterial],
+    /// Colors that rotate while the tileset is on screen.
+    pub cycles: &'static [ColorCycle],
+}
+
+/// Collision shape of a tile, measured from the tileset's collision mask.
+pub struct TileShape {
+    /// Solid pixels in each column, counted from the bottom, or from the top if negative.
+    pub heights: [i8; 16],
+    /// Solid pixels in each row, counted from the right, or from the left if negative.
+    pub widths: [i8; 16],
+    /// Steepness of the open edge, in half pixels down per pixel to the right.
+    pub angle: i8,
+}
+
+impl TileShape {
+    /// Range of solid pixels in a column, from the top of the tile.
+    pub fn column(&self, x: i32) -> Option<(i32, i32)> {
+        span(self.heights[x as usize & 15])
+    }
+    /// Range of solid pixels in a row, from the left of the tile.
+    pub fn row(&self, y: i32) -> Option<(i32, i32)> {
+        span(self.widths[y as usize & 15])
+    }
+    /// Whether the solid part of this tile hangs from its top.
+    pub fn is_ceiling(&self) -> bool {
+        self.heights.iter().any(|&h| h < 0)
+    }
+    pub fn is_solid(&self, at: Vec2<i32>) -> bool {
+        matches!(self.column(at.x), Some((a, b)) if (a..b).contains(&(at.y & 15)))
+    }
+}
+
+fn span(length: i8) -> Option<(i32, i32)> {
+    match length as i32 {
+        0 => None,
+        l if l > 0 => Some((16 - l, 16)),
+        l => Some((0, -l))
     }
 }
 
 pub fn init() {
     unsafe { lz4::decompress(&GFX_DATA_LZ4, &mut GFX_DATA) };
+    palette::init();
 }
 
 pub fn draw_text<S: Surface>(fb: &mut S, position: &mut Vec2<i32>, msg: &[u8]) {
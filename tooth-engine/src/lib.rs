@@ -23,8 +23,11 @@ pub mod controller;
 pub mod graphics;
 pub mod foreground;
 pub mod background;
+pub mod raster;
+pub mod palette;
 pub mod terrain;
 pub mod state;
 pub mod entity;
 pub mod lz4;
 pub mod rand;
+pub mod trig;
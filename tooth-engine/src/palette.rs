@@ -0,0 +1,69 @@
+//! Colors the game is drawn with, changed as it runs.
+//!
+//! `graphics::PAL_DATA` holds the palettes as they were drawn. Each frame they're copied into
+//! `graphics::PALETTE` with color cycles turned and the screen fade applied, and everything is
+//! drawn from there. Colors that don't come from a palette go through `faded` to match.
+
+use crate::graphics::{PAL_DATA, PALETTE};
+use crate::raster;
+
+/// Colors of a palette that rotate, for things like flowing lava.
+pub struct ColorCycle {
+    /// Frames each step of the rotation lasts.
+    pub speed: u16,
+    /// The colors, as indices into `graphics::PAL_DATA`. Each one takes on the next in turn.
+    pub colors: &'static [u16],
+}
+
+pub const BLACK: u32 = 0xFF000000;
+pub const WHITE: u32 = 0xFFFFFFFF;
+
+/// Color the whole screen is blended with, and how much of it out of 256.
+static mut FADE: (u32, i32) = (BLACK, 0);
+
+/// Resets the palettes to how they were drawn.
+pub fn init() {
+    unsafe { PALETTE = PAL_DATA };
+    set_fade(BLACK, 0);
+}
+
+/// Works out the palettes for a frame, from the color cycles of what's on screen and the fade.
+pub fn update(cycles: &[ColorCycle], timer: i32, fade: (u32, i32)) {
+    let palette = unsafe { &mut *core::ptr::addr_of_mut!(PALETTE) };
+    palette.copy_from_slice(&PAL_DATA);
+    for cycle in cycles {
+        let len = cycle.colors.len();
+        let step = (timer as u32 / cycle.speed.max(1) as u32) as usize;
+        for (i, &index) in cycle.colors.iter().enumerate() {
+            palette[index as usize] = PAL_DATA[cycle.colors[(i + step) % len] as usize];
+        }
+    }
+    set_fade(fade.0, fade.1);
+    if fade.1 != 0 {
+        for c in palette.iter_mut().filter(|c| **c != 0) {
+            *c = raster::blend(*c, fade.0, fade.1);
+        }
+    }
+}
+
+fn set_fade(color: u32, amount: i32) {
+    unsafe { FADE = (color, amount) };
+}
+
+/// Applies the screen fade to a color that isn't in a palette.
+pub fn faded(color: u32) -> u32 {
+    let (fade, amount) = unsafe { FADE };
+    if amount == 0 { return color; }
+    raster::blend(color, fade, amount)
+}
+
+/// A copy of a palette blended with a color, for flashing a single sprite.
+///
+/// Transparent colors stay transparent.
+pub fn tinted(pal: &[u32], color: u32, amount: i32) -> [u32; 256] {
+    let mut out = [0; 256];
+    for (c, &from) in out.iter_mut().zip(pal) {
+        if from != 0 { *c = raster::blend(from, color, amount); }
+    }
+    out
+}
@@ -0,0 +1,118 @@
+//! Per-line effects on how the screen is drawn, like the SNES's HDMA.
+//!
+//! Effects come from the background theme and the level. Each frame they're worked out into
+//! `Scanlines`, which the background and foreground renderers follow.
+
+use crate::framebuffer::Framebuffer;
+use crate::palette;
+use crate::trig;
+use crate::vec2::Vec2;
+
+const HEIGHT: usize = Framebuffer::HEIGHT;
+
+/// An effect on a range of lines of the screen.
+pub struct RasterEffect {
+    pub kind: RasterKind,
+    /// First and last line it touches.
+    pub lines: (i32, i32),
+    pub target: RasterTarget,
+}
+
+pub enum RasterKind {
+    /// Shifts lines right by a table of pixels, repeated down the lines.
+    Offsets(&'static [i8]),
+    /// Shifts lines along a sine wave, for heat haze or being underwater.
+    Wave {
+        /// Pixels the lines move either way.
+        amplitude: i32,
+        /// Lines the wave takes to repeat.
+        period: i32,
+        /// How fast the wave moves, in 1/256ths of a turn per frame.
+        speed: i32,
+    },
+    /// Blends lines with a color, by `from` out of 256 on the first line to `to` on the last one.
+    Gradient { color: u32, from: i32, to: i32 },
+    /// Lines scroll this far when the camera moves horizontally, in 1/256ths, as their own region of the screen.
+    Split { scroll: i32 },
+}
+
+/// Which layers an effect applies to.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum RasterTarget {
+    Background,
+    Foreground,
+    All,
+}
+
+impl RasterTarget {
+    pub fn includes(self, other: RasterTarget) -> bool {
+        self == RasterTarget::All || self == other
+    }
+}
+
+/// How each line of the screen is drawn on a frame.
+pub struct Scanlines {
+    /// Horizontal position of the camera on each line.
+    pub camera_x: [i32; HEIGHT],
+    /// How far each line is shifted right, after scrolling.
+    pub shift: [i32; HEIGHT],
+    /// Color each line is blended with, and how much of it out of 256.
+    pub tint: [(u32, i32); HEIGHT],
+}
+
+impl Scanlines {
+    /// Every line drawn normally.
+    pub fn flat(camera: Vec2<i32>) -> Scanlines {
+        Scanlines {
+            camera_x: [camera.x; HEIGHT],
+            shift: [0; HEIGHT],
+            tint: [(0, 0); HEIGHT],
+        }
+    }
+    /// Works out the lines for layers of `target`, from the effects that apply to them.
+    ///
+    /// Offsets and waves add up, for gradients and splits the last effect wins.
+    pub fn new<'a>(effects: impl Iterator<Item=&'a RasterEffect>, target: RasterTarget, camera: Vec2<i32>, timer: i32) -> Scanlines {
+        let mut lines = Scanlines::flat(camera);
+        for effect in effects.filter(|c| c.target.includes(target)) {
+            let first = effect.lines.0.max(0);
+            let last = effect.lines.1.min(HEIGHT as i32 - 1);
+            for y in first..=last {
+                let line = y - first;
+                let i = y as usize;
+                match effect.kind {
+                    RasterKind::Offsets(table) => {
+                        lines.shift[i] += table[line as usize % table.len()] as i32;
+                    }
+                    RasterKind::Wave { amplitude, period, speed } => {
+                        let angle = line * 256 / period.max(1) + timer.wrapping_mul(speed);
+                        lines.shift[i] += amplitude * trig::sin(angle) / 256;
+                    }
+                    RasterKind::Gradient { color, from, to } => {
+                        let amount = from + (to - from) * line / (last - first).max(1);
+                        lines.tint[i] = (palette::faded(color), amount);
+                    }
+                    RasterKind::Split { scroll } => {
+                        lines.camera_x[i] = camera.x * scroll / 256;
+                    }
+                }
+            }
+        }
+        lines
+    }
+    /// Blends a pixel with the color of its line.
+    pub fn tint(&self, y: usize, px: u32) -> u32 {
+        let (color, amount) = self.tint[y];
+        if amount == 0 { return px; }
+        blend(px, color, amount)
+    }
+}
+
+/// Blends two colors, taking `amount` out of 256 of the second, and the alpha of the first.
+pub fn blend(a: u32, b: u32, amount: i32) -> u32 {
+    let amount = amount.clamp(0, 256) as u32;
+    let channel = |c: u32, i: u32| (c >> (i * 8)) & 0xFF;
+    (0..3).fold(a & 0xFF000000, |color, i| {
+        color | ((channel(a, i) * (256 - amount) + channel(b, i) * amount) / 256) << (i * 8)
+    })
+}
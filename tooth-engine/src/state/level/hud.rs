@@ -1,7 +1,7 @@
 use super::LevelState;
 use crate::framebuffer::Framebuffer;
 use crate::vec2::{vec2, Vec2};
-use crate::graphics::{self, DUNE_FG, BOLDFACE};
+use crate::graphics::{self, BOLDFACE};
 
 pub struct Hud {
     textbox: Textbox,
@@ -75,6 +75,8 @@ pub struct Textbox {
 }
 
 impl Textbox {
+    /// Colors of the border and the two checkerboard squares.
+    const PALETTE: [u32; 3] = [0xFF_08_27_45, 0xFF_30_70_B1, 0xFF_24_51_79];
     pub const fn empty() -> Self {
         Self {
             msg: None,
@@ -104,13 +106,13 @@ impl Textbox {
                 for j in vstart..vend {
                     *fb.pixel(vec2(i,j)).unwrap() = if i == hstart || i == hend-1 || j == vstart || j == vend-1 {
                         //0xFF83212c
-                        DUNE_FG.get_pal()[5]
+                        Self::PALETTE[0]
                     } else if ((i + self.timer / 2) / 16) % 2 != ((j + self.timer / 2) / 16) % 2 {
                         //0xFFcc2b32
-                        DUNE_FG.get_pal()[2]
+                        Self::PALETTE[1]
                     } else {
                         //0xFFd04a61
-                        DUNE_FG.get_pal()[3]
+                        Self::PALETTE[2]
                     };
                 }
             }
@@ -3,14 +3,19 @@ use super::GameState;
 use crate::framebuffer::Framebuffer;
 use crate::controller::Buttons;
 use crate::vec2::{Vec2, vec2};
-use crate::foreground::Foreground;
+use crate::foreground::{Foreground, LayerId};
 use crate::background::Background;
-use crate::entity::{self, EntitySet};
+use crate::raster::{RasterEffect, RasterTarget, Scanlines};
+use crate::palette;
+use crate::entity::{self, EntitySet, EntityId, ContactKind};
+use crate::foreground::{Solidity, BlockHit, Prize};
 use crate::terrain;
 use crate::graphics;
 
 pub mod hud;
 
+static LEVEL: &[u8] = include_bytes!("../../../../level_demo.bin");
+
 pub struct LevelState {
     pub camera: Vec2<i32>,
     pub foreground: Foreground,
@@ -26,43 +31,102 @@ pub struct LevelData {
     pub fadein_timer: i32,
     pub coins: i32,
     pub score: i32,
-    pub timer: Option<i32>
+    pub timer: Option<i32>,
+    /// Index of the loaded area.
+    pub area: u8,
+    /// Warp the player is going through, if any.
+    pub warp: Option<WarpTransition>,
+    /// Raster effects of the area, on top of the background's.
+    pub effects: &'static [RasterEffect],
+}
+
+/// Fading out on the way to another area, which is loaded once the screen is dark.
+#[derive(Copy, Clone)]
+pub struct WarpTransition {
+    pub area: u8,
+    /// Index of the warp in the new area to come out of.
+    pub exit: u8,
+    /// Radius of the circle of screen still visible around the player.
+    pub radius: i32,
+}
+
+impl LevelData {
+    /// Starts fading out to another area, unless already on the way to one.
+    pub fn start_warp(&mut self, area: u8, exit: u8) {
+        if self.warp.is_none() {
+            let radius = Framebuffer::size().x.max(Framebuffer::size().y);
+            self.warp = Some(WarpTransition { area, exit, radius });
+        }
+    }
 }
 
 impl LevelState {
     pub fn new() -> Self {
-        use crate::graphics;
-        let background = Background::new();
-        let mut foreground = Foreground::new();
-        decomp_level(&mut foreground);
-        let mut entity_set = EntitySet::new();
-        //entity_set.spawn(entity::star(vec2(1480, 60) * 256));
-        entity_set.spawn(entity::key(vec2(0x288, 0x23F) * 256));
-        entity_set.spawn(entity::lock(vec2(1640, 0x2CE) * 256));
-        entity_set.spawn(entity::sign(vec2(472, 0x2BF) * 256, b"THIS IS REALLY LARGE TEXT,YOU\nHAVE TO WRAP IT"));
-        entity_set.spawn(entity::sign(vec2(0x2F7, 0x2DF) * 256, b"THIS IS GIANT TEXT.\nBIG.\nTREMENDOUS.\nFANTASTIC.\nMANY LINES."));
-        //entity_set.spawn(entity::tomato(vec2(400, 60) * 256));
-        entity_set.player.set_pos(vec2(0x68, 0x2BF) * 256);
-        LevelState {
+        let mut state = LevelState {
             camera: vec2(0,60),
             data: LevelData {
-                level_size: vec2(4096, 2048),
+                level_size: vec2(0, 0),
                 fadein_timer: 0,
                 coins: 0,
                 score: 0,
-                timer: Some(0)
+                timer: Some(0),
+                area: 0,
+                warp: None,
+                effects: &[],
             },
-            foreground,
-            background,
-            entity_set,
+            foreground: Foreground::new(),
+            background: Background::new(),
+            entity_set: EntitySet::new(),
             hud: hud::Hud::new(),
             buttons: Buttons::new()
+        };
+        // A broken level still loads as far as it could be read, instead of stopping the game
+        let _ = state.load_area(0, None);
+        state.camera = vec2(0,60);
+        state
+    }
+    /// Replaces the loaded area, putting the player at one of its warps, or where the area starts.
+    pub fn load_area(&mut self, id: u8, exit: Option<u8>) -> Result<(), terrain::DecodeError> {
+        let area = terrain::find_level_area(LEVEL, id)?;
+        let start = match exit {
+            Some(exit) => {
+                let warp = area.warps().nth(exit as usize).ok_or(terrain::DecodeError::MissingWarp(exit))?;
+                entity::warp_point(&warp)
+            }
+            None => entity::start_point(area.header.start),
+        };
+        let res = decomp_level(&mut self.foreground, &mut self.background, &area);
+        self.data.area = id;
+        self.data.effects = graphics::RASTER_EFFECTS.get(area.header.effects as usize).copied().unwrap_or(&[]);
+        self.data.level_size = self.foreground.size() * 16;
+        self.hud.hide_textbox();
+        self.entity_set.clear();
+        for spawn in area.spawns() {
+            self.entity_set.spawn(entity::spawn(&spawn));
+        }
+        for warp in area.warps() {
+            self.entity_set.spawn(entity::warp(warp));
         }
+        self.entity_set.player.set_pos(start);
+        self.entity_set.player.data_mut().vel = vec2(0, 0);
+        self.camera = start / 256 - Framebuffer::size() / 2 + vec2(0, 16);
+        res
     }
     pub fn run(&mut self, fb: &mut Framebuffer, buttons: Buttons) -> Option<GameState> {
         self.buttons = buttons;
+        if let Some(warp) = self.data.warp.filter(|c| c.radius <= 0) {
+            self.data.warp = None;
+            self.data.fadein_timer = 0;
+            let _ = self.load_area(warp.area, Some(warp.exit));
+        }
         let self_ptr = self as *mut _;
-        self.entity_set.run(self_ptr);
+        // Everything stands still while fading out
+        if self.data.warp.is_none() {
+            self.foreground.tick();
+            self.background.tick();
+            self.entity_set.run(self_ptr);
+            self.handle_events();
+        }
 
         let camera_target = self.entity_set.player.pos() / 256 - Framebuffer::size() / 2 + vec2(0, 16);
 
@@ -82,29 +146,142 @@ impl LevelState {
             |c,m| c.max(0).min(m)
         );
 
-        self.background.render(self.camera, fb);
-        self.foreground.render(self.camera, fb);
+        let timer = self.background.timer();
+        palette::update(self.foreground.color_cycles(), timer, self.screen_fade());
+        let effects = || self.background.effects().iter().chain(self.data.effects);
+        let bg_lines = Scanlines::new(effects(), RasterTarget::Background, self.camera, timer);
+        let fg_lines = Scanlines::new(effects(), RasterTarget::Foreground, self.camera, timer);
+        self.background.render(self.camera, &bg_lines, fb);
+        self.foreground.render(self.camera, &fg_lines, fb);
         self.entity_set.render(self.camera, fb);
+        self.foreground.render_front(self.camera, &fg_lines, fb);
 
         self.hud.render(fb, self_ptr);
 
 
-        if self.data.fadein_timer < Framebuffer::size().x.max(Framebuffer::size().y) {
-            self.data.fadein_timer += 8;
+        let radius = match &mut self.data.warp {
+            Some(warp) => {
+                warp.radius -= 8;
+                Some(warp.radius.max(0))
+            }
+            None if self.data.fadein_timer < Framebuffer::size().x.max(Framebuffer::size().y) => {
+                self.data.fadein_timer += 8;
+                Some(self.data.fadein_timer)
+            }
+            None => None
+        };
+        if let Some(radius) = radius {
             let center = self.entity_set.player.pos() / 256 - self.camera - vec2(0, 24);
             for (pos,px) in fb.pixels() {
                 let dist = pos - center;
-                if dist.x*dist.x + dist.y*dist.y > self.data.fadein_timer*self.data.fadein_timer {
+                if dist.x*dist.x + dist.y*dist.y > radius*radius {
                     *px = 0xFF000000;
                 }
             }
         }
         None
     }
+    /// Color the screen is faded with this frame, and how much of it out of 256.
+    fn screen_fade(&self) -> (u32, i32) {
+        const RED: u32 = 0xFF0000FF;
+        let size = Framebuffer::size().x.max(Framebuffer::size().y);
+        let flash = self.entity_set.player.hurt_flash();
+        // Darker the smaller the circle around the player is
+        if let Some(warp) = self.data.warp {
+            (palette::BLACK, 256 - warp.radius.max(0) * 256 / size)
+        } else if self.data.fadein_timer < size {
+            (palette::BLACK, 256 - self.data.fadein_timer * 256 / size)
+        } else if flash > 0 {
+            (RED, flash)
+        } else {
+            (palette::BLACK, 0)
+        }
+    }
+    /// Reacts to the tiles entities touched this frame.
+    fn handle_events(&mut self) {
+        for event in self.entity_set.events.take() {
+            let tile = event.contact.tile;
+            match (event.source, event.contact.kind) {
+                (EntityId::Player, ContactKind::Coin) => {
+                    // Several sensors may have touched the same coin
+                    if self.foreground.solidity_at(tile) != Solidity::Coin { continue; }
+                    *self.foreground.block_at_mut(tile) = 0;
+                    self.entity_set.spawn(entity::explosion((tile * 16 + vec2(8, 14)) * 256));
+                    self.data.coins += 1;
+                }
+                (EntityId::Player, ContactKind::Bump) => self.hit_block(tile),
+                (EntityId::Player, ContactKind::Hurt) => self.entity_set.player.hurt(),
+                // Landing only breaks blocks, it doesn't give out prizes
+                (EntityId::Player, ContactKind::Stomp) if self.foreground.props_at(tile).hit == BlockHit::Shatter => {
+                    self.hit_block(tile);
+                }
+                _ => {}
+            }
+        }
+    }
+    /// Gives out a block's prize or breaks it, and knocks over anything standing on it.
+    fn hit_block(&mut self, tile: Vec2<i32>) {
+        let center = (tile * 16 + vec2(8, 8)) * 256;
+        match self.foreground.props_at(tile).hit {
+            BlockHit::None => return,
+            BlockHit::Prize { prize, used } => {
+                *self.foreground.block_at_mut(tile) = used;
+                self.foreground.bump(tile);
+                match prize {
+                    Prize::Coin => {
+                        self.entity_set.spawn(entity::coin_pop(center - vec2(0, 8) * 256));
+                        self.data.coins += 1;
+                    }
+                    Prize::Star => self.entity_set.spawn(entity::star(center - vec2(0, 9) * 256)),
+                }
+            }
+            BlockHit::Shatter => {
+                *self.foreground.block_at_mut(tile) = 0;
+                for &(x, y) in [(-1, -1), (1, -1), (-1, 1), (1, 1)].iter() {
+                    let vel = vec2(x * 0x100, -0x400 + y * 0x180);
+                    self.entity_set.spawn(entity::debris(center + vec2(x, y) * 4 * 256, vel));
+                }
+            }
+        }
+        for i in self.entity_set.list.iter_mut().flatten() {
+            let on_top = i.data.pos / 256 - tile * 16;
+            if (-8..24).contains(&on_top.x) && (-2..2).contains(&on_top.y) {
+                i.bump();
+            }
+        }
+    }
 }
 
 
-pub fn decomp_level(foreground: &mut Foreground) {
-    terrain::decode_area(foreground.blocks_mut(), include_bytes!("../../../../level_demo.bin"));
+/// Sets up the foreground and background for an area of the level.
+pub fn decomp_level(foreground: &mut Foreground, background: &mut Background, area: &terrain::LevelArea) -> Result<(), terrain::DecodeError> {
+    let header = area.header;
+    foreground.set_tileset(header.tileset);
+    foreground.set_size(header.size.map(|c| c as i32));
+    foreground.water_level = header.water_level.map(|c| c as i32 * 16);
+    background.set_theme(header.background);
+    for &id in [LayerId::Back, LayerId::Main, LayerId::Front].iter() {
+        let layer = foreground.layer_mut(id);
+        layer.visible = id == LayerId::Main;
+        layer.scroll = vec2(256, 256);
+    }
+    let mut clipped = Ok(());
+    for section in area.sections() {
+        let id = match LayerId::from_u8(section.layer) {
+            Some(c) => c,
+            None => continue
+        };
+        let layer = foreground.layer_mut(id);
+        layer.visible = true;
+        layer.scroll = section.scroll.map(|c| c as i32 * 16);
+        let too_large = terrain::DecodeError::BadSize { width: header.size.x, height: header.size.y };
+        let mut map = foreground.layer_map(id).ok_or(too_large)?;
+        // Objects hanging off the level don't stop the other layers from loading
+        match terrain::decode_area(&mut map, section.area, &header) {
+            Err(e @ terrain::DecodeError::OutOfBounds { .. }) => clipped = clipped.and(Err(e)),
+            res => res?,
+        }
+    }
+    clipped
 }
 
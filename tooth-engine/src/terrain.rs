@@ -1,169 +1,668 @@
 use crate::vec2::{vec2, Vec2};
 use core::slice::Iter;
+use core::fmt;
 use crate::rand::RandState;
+use crate::graphics;
+use crate::foreground::MAX_BLOCKS;
 
-pub fn decode_area(buf: &mut [u8], mut src: &[u8]) {
-    let len = ((src[0] as usize) << 8) + src[1] as usize;
-    src = &src[2..];
-    decode_land(buf, &src[..len]);
-    src = &src[len..];
-    while src.len() != 0 {
-        let old = src;
-        decode_object(buf, &mut src);
+/// Why part of a level couldn't be decoded.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DecodeError {
+    /// The data ends in the middle of something.
+    Truncated,
+    /// An object id `decode_object` doesn't know, so the rest of the data can't be read either.
+    UnknownObject(u8),
+    /// An object or land chunk at this position (in blocks) reaches outside the map.
+    ///
+    /// The part inside the map is still placed.
+    OutOfBounds { x: u16, y: u16 },
+    /// A length prefix doesn't fit the data it's in.
+    LengthMismatch,
+    /// A warp kind `Warp::decode` doesn't know.
+    UnknownWarp(u8),
+    /// An entity kind `Spawn::decode` doesn't know.
+    UnknownSpawn(u8),
+    /// The level has no area with this index.
+    MissingArea(u8),
+    /// The area has no warp with this index to come out of.
+    MissingWarp(u8),
+    /// The area (in blocks) is empty, or its layers have more blocks than `foreground::MAX_BLOCKS`.
+    BadSize { width: u16, height: u16 },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "level data ends too early"),
+            DecodeError::UnknownObject(id) => write!(f, "unknown object id {:02X}", id),
+            DecodeError::OutOfBounds { x, y } => write!(f, "object at {:X},{:X} reaches outside the level", x, y),
+            DecodeError::LengthMismatch => write!(f, "length doesn't match the level data"),
+            DecodeError::UnknownWarp(kind) => write!(f, "unknown warp kind {:02X}", kind),
+            DecodeError::UnknownSpawn(kind) => write!(f, "unknown entity kind {:02X}", kind),
+            DecodeError::MissingArea(id) => write!(f, "level has no area {}", id),
+            DecodeError::MissingWarp(id) => write!(f, "area has no warp {}", id),
+            DecodeError::BadSize { width, height } => write!(f, "area size {}x{} is empty or too large", width, height),
+        }
+    }
+}
+
+/// Settings stored at the start of each area of a level, before its terrain.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct LevelHeader {
+    /// Index into `graphics::TILESETS`.
+    pub tileset: u8,
+    /// Index into `graphics::BACKGROUNDS`.
+    pub background: u8,
+    /// Index into `graphics::RASTER_EFFECTS`, 0 for none.
+    pub effects: u8,
+    /// Whether block ids are stored as two bytes instead of one.
+    pub wide_blocks: bool,
+    /// Size of the level, in blocks.
+    pub size: Vec2<u16>,
+    /// Row of blocks the water line is at, if there is one. Everything below it is underwater.
+    pub water_level: Option<u16>,
+    /// The block the player starts in when entering the area other than through a warp.
+    pub start: Vec2<u16>,
+}
+
+impl Default for LevelHeader {
+    fn default() -> Self {
+        LevelHeader {
+            tileset: 0,
+            background: 0,
+            effects: 0,
+            wide_blocks: false,
+            size: vec2(256, 128),
+            water_level: None,
+            start: vec2(6, 43),
+        }
     }
 }
 
-pub fn decode_land_chunk(src: &mut &[u8]) -> [u8; 5] {
-    let mut buf = [0; 5];
-    buf.copy_from_slice(&src[..5]);
-    *src = &src[5..];
-    buf
+impl LevelHeader {
+    pub const LEN: usize = 14;
+    const WIDE_BLOCKS: u8 = 0x01;
+    /// Water level of an area without any water.
+    const NO_WATER: u16 = 0xFFFF;
+
+    /// Land materials of the level's tileset, falling back to the first tileset like `Foreground::set_tileset`.
+    pub fn land_materials(&self) -> &'static [LandMaterial] {
+        graphics::TILESETS.get(self.tileset as usize).unwrap_or(&graphics::TILESETS[0]).land
+    }
+    pub fn decode(src: &mut &[u8]) -> Result<Self, DecodeError> {
+        let tileset = read_u8(src)?;
+        let background = read_u8(src)?;
+        let effects = read_u8(src)?;
+        let flags = read_u8(src)?;
+        let size = vec2(read_u16(src)?, read_u16(src)?);
+        if size.x == 0 || size.y == 0 || size.x as usize * size.y as usize > MAX_BLOCKS {
+            return Err(DecodeError::BadSize { width: size.x, height: size.y });
+        }
+        let water_level = read_u16(src)?;
+        let start = vec2(read_u16(src)?, read_u16(src)?);
+        if start.x >= size.x || start.y >= size.y {
+            return Err(DecodeError::OutOfBounds { x: start.x, y: start.y });
+        }
+        Ok(LevelHeader {
+            tileset,
+            background,
+            effects,
+            wide_blocks: flags & Self::WIDE_BLOCKS != 0,
+            size,
+            water_level: Some(water_level).filter(|&c| c != Self::NO_WATER),
+            start,
+        })
+    }
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let [w0, w1] = self.size.x.to_be_bytes();
+        let [h0, h1] = self.size.y.to_be_bytes();
+        let [l0, l1] = self.water_level.unwrap_or(Self::NO_WATER).to_be_bytes();
+        let [x0, x1] = self.start.x.to_be_bytes();
+        let [y0, y1] = self.start.y.to_be_bytes();
+        let flags = if self.wide_blocks { Self::WIDE_BLOCKS } else { 0 };
+        [self.tileset, self.background, self.effects, flags, w0, w1, h0, h1, l0, l1, x0, x1, y0, y1]
+    }
+    /// Reads a block id, one or two bytes long depending on the level.
+    fn read_block(&self, src: &mut &[u8]) -> Result<u16, DecodeError> {
+        if self.wide_blocks {
+            read_u16(src)
+        } else {
+            read_u8(src).map(|c| c as u16)
+        }
+    }
+}
+
+fn read_u8(src: &mut &[u8]) -> Result<u8, DecodeError> {
+    let (&c, rest) = src.split_first().ok_or(DecodeError::Truncated)?;
+    *src = rest;
+    Ok(c)
+}
+
+fn read_u16(src: &mut &[u8]) -> Result<u16, DecodeError> {
+    Ok(u16::from_be_bytes([read_u8(src)?, read_u8(src)?]))
+}
+
+/// Splits `len` bytes off the front of `src`.
+fn read_slice<'a>(src: &mut &'a [u8], len: usize) -> Result<&'a [u8], DecodeError> {
+    if len > src.len() { return Err(DecodeError::LengthMismatch); }
+    let (data, rest) = src.split_at(len);
+    *src = rest;
+    Ok(data)
+}
+
+/// The part of a level that describes one of its layers.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct LayerSection<'a> {
+    /// Which layer this is, see `foreground::LayerId`.
+    pub layer: u8,
+    /// How far the layer moves when the camera does, in 1/16ths.
+    pub scroll: Vec2<u8>,
+    /// The layer's blocks, to be read by `decode_area`.
+    pub area: &'a [u8],
+}
+
+impl LayerSection<'_> {
+    /// Writes the section out byte by byte, in the format `decode_layer_section` reads.
+    pub fn encode(&self, mut out: impl FnMut(u8)) {
+        out(self.layer);
+        out(self.scroll.x);
+        out(self.scroll.y);
+        (self.area.len() as u16).to_be_bytes().iter().for_each(|&c| out(c));
+        self.area.iter().for_each(|&c| out(c));
+    }
+}
+
+/// Reads the next layer of an area.
+pub fn decode_layer_section<'a>(src: &mut &'a [u8]) -> Result<LayerSection<'a>, DecodeError> {
+    let layer = read_u8(src)?;
+    let scroll = vec2(read_u8(src)?, read_u8(src)?);
+    let len = read_u16(src)? as usize;
+    let area = read_slice(src, len)?;
+    Ok(LayerSection { layer, scroll, area })
+}
+
+/// How a warp is entered.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum WarpKind {
+    /// Entered by pressing up in front of it.
+    Door,
+    /// Entered by pressing down while standing on top of it.
+    Pipe,
+}
+
+/// A way into another area of the level, which is also where the player comes out when warping to it.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Warp {
+    pub kind: WarpKind,
+    /// The block the player stands in to enter it, or when coming out of it.
+    ///
+    /// For pipes that's the left one of the two above the pipe.
+    pub pos: Vec2<u16>,
+    /// Index of the area it leads to.
+    pub area: u8,
+    /// Index of the warp in that area the player comes out of.
+    pub exit: u8,
+}
+
+impl Warp {
+    pub const LEN: usize = 7;
+
+    pub fn decode(src: &mut &[u8]) -> Result<Self, DecodeError> {
+        let kind = match read_u8(src)? {
+            0 => WarpKind::Door,
+            1 => WarpKind::Pipe,
+            c => return Err(DecodeError::UnknownWarp(c)),
+        };
+        Ok(Warp {
+            kind,
+            pos: vec2(read_u16(src)?, read_u16(src)?),
+            area: read_u8(src)?,
+            exit: read_u8(src)?,
+        })
+    }
+    /// Writes the warp out byte by byte, in the format `decode` reads.
+    pub fn encode(&self, mut out: impl FnMut(u8)) {
+        out(self.kind as u8);
+        for c in [self.pos.x, self.pos.y].iter() {
+            c.to_be_bytes().iter().for_each(|&c| out(c));
+        }
+        out(self.area);
+        out(self.exit);
+    }
+}
+
+/// What kind of entity a `Spawn` places.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SpawnKind {
+    Star,
+    Key,
+    Lock,
+    /// Shows one of `graphics::MESSAGES`, picked by the spawn's `arg`.
+    Sign,
+    /// Uses one of `graphics::MISC_VARIANTS`, picked by the spawn's `arg`.
+    Tomato,
+}
+
+/// An entity placed in an area, spawned each time the area is entered.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Spawn {
+    pub kind: SpawnKind,
+    /// Where it starts, in pixels.
+    pub pos: Vec2<u16>,
+    /// Sets it up further, depending on the kind.
+    pub arg: u8,
+}
+
+impl Spawn {
+    pub const LEN: usize = 6;
+
+    pub fn decode(src: &mut &[u8]) -> Result<Self, DecodeError> {
+        let kind = match read_u8(src)? {
+            0 => SpawnKind::Star,
+            1 => SpawnKind::Key,
+            2 => SpawnKind::Lock,
+            3 => SpawnKind::Sign,
+            4 => SpawnKind::Tomato,
+            c => return Err(DecodeError::UnknownSpawn(c)),
+        };
+        Ok(Spawn {
+            kind,
+            pos: vec2(read_u16(src)?, read_u16(src)?),
+            arg: read_u8(src)?,
+        })
+    }
+    /// Writes the spawn out byte by byte, in the format `decode` reads.
+    pub fn encode(&self, mut out: impl FnMut(u8)) {
+        out(self.kind as u8);
+        for c in [self.pos.x, self.pos.y].iter() {
+            c.to_be_bytes().iter().for_each(|&c| out(c));
+        }
+        out(self.arg);
+    }
+}
+
+/// One area of a level, like the main stage or a bonus room, with its own size, tileset and background.
+#[derive(Copy, Clone)]
+pub struct LevelArea<'a> {
+    pub header: LevelHeader,
+    warps: &'a [u8],
+    spawns: &'a [u8],
+    sections: &'a [u8],
+    encoded: &'a [u8],
+}
+
+impl<'a> LevelArea<'a> {
+    /// The warps leading out of this area, by index.
+    pub fn warps(&self) -> impl Iterator<Item=Warp> + 'a {
+        // Already checked by `decode_level_area`
+        self.warps.chunks(Warp::LEN).filter_map(|mut c| Warp::decode(&mut c).ok())
+    }
+    /// The entities placed in this area.
+    pub fn spawns(&self) -> impl Iterator<Item=Spawn> + 'a {
+        // Already checked by `decode_level_area`
+        self.spawns.chunks(Spawn::LEN).filter_map(|mut c| Spawn::decode(&mut c).ok())
+    }
+    /// The layers of this area.
+    pub fn sections(&self) -> impl Iterator<Item=LayerSection<'a>> {
+        let mut src = self.sections;
+        core::iter::from_fn(move || decode_layer_section(&mut src).ok())
+    }
+    /// The whole area, as it was encoded.
+    pub fn encoded(&self) -> &'a [u8] {
+        self.encoded
+    }
+}
+
+/// Reads the next area of a level.
+///
+/// An area is a header, then a count and list of warps, then a count and list of entity spawns,
+/// then a count and list of layer sections.
+pub fn decode_level_area<'a>(src: &mut &'a [u8]) -> Result<LevelArea<'a>, DecodeError> {
+    let start = *src;
+    let header = LevelHeader::decode(src)?;
+    let warp_count = read_u8(src)? as usize;
+    let warps = *src;
+    for _ in 0..warp_count {
+        Warp::decode(src)?;
+    }
+    let warps = &warps[..warps.len() - src.len()];
+    let spawn_count = read_u8(src)? as usize;
+    let spawns = *src;
+    for _ in 0..spawn_count {
+        Spawn::decode(src)?;
+    }
+    let spawns = &spawns[..spawns.len() - src.len()];
+    let section_count = read_u8(src)?;
+    let sections = *src;
+    for _ in 0..section_count {
+        decode_layer_section(src)?;
+    }
+    let sections = &sections[..sections.len() - src.len()];
+    Ok(LevelArea { header, warps, spawns, sections, encoded: &start[..start.len() - src.len()] })
+}
+
+/// Writes out an area, in the format `decode_level_area` reads.
+pub fn encode_level_area(header: &LevelHeader, warps: &[Warp], spawns: &[Spawn], sections: &[LayerSection], mut out: impl FnMut(u8)) {
+    header.encode().iter().for_each(|&c| out(c));
+    out(warps.len() as u8);
+    warps.iter().for_each(|c| c.encode(&mut out));
+    out(spawns.len() as u8);
+    spawns.iter().for_each(|c| c.encode(&mut out));
+    out(sections.len() as u8);
+    sections.iter().for_each(|c| c.encode(&mut out));
+}
+
+/// Reads the areas of a level, which start with how many there are.
+pub fn decode_level_areas(mut src: &[u8]) -> Result<impl Iterator<Item=Result<LevelArea<'_>, DecodeError>>, DecodeError> {
+    let mut count = read_u8(&mut src)?;
+    Ok(core::iter::from_fn(move || {
+        if count == 0 { return None; }
+        let area = decode_level_area(&mut src);
+        // Nothing after a broken area can be found
+        count = if area.is_ok() { count - 1 } else { 0 };
+        Some(area)
+    }))
+}
+
+/// Finds one of the areas of a level.
+pub fn find_level_area(src: &[u8], id: u8) -> Result<LevelArea<'_>, DecodeError> {
+    decode_level_areas(src)?.nth(id as usize).unwrap_or(Err(DecodeError::MissingArea(id)))
+}
+
+/// The blocks of a level, stored row by row.
+pub struct BlockMap<'a> {
+    pub blocks: &'a mut [u16],
+    pub width: usize,
+    /// Whether anything was placed outside of the map since this was last reset.
+    clipped: bool,
+}
+
+impl<'a> BlockMap<'a> {
+    pub fn new(blocks: &'a mut [u16], width: usize) -> Self {
+        BlockMap { blocks, width, clipped: false }
+    }
+    pub fn height(&self) -> usize {
+        self.blocks.len() / self.width
+    }
+    /// Returns the block at a position, or 0 outside of the map.
+    pub fn get(&self, x: usize, y: usize) -> u16 {
+        if x >= self.width { return 0; }
+        let index = y.checked_mul(self.width).and_then(|c| c.checked_add(x));
+        index.and_then(|c| self.blocks.get(c)).copied().unwrap_or(0)
+    }
+    /// Places a block. Positions outside of the map are skipped, and remembered as clipped.
+    pub fn set(&mut self, x: usize, y: usize, block: u16) {
+        let index = y.checked_mul(self.width).and_then(|c| c.checked_add(x));
+        match index.filter(|_| x < self.width).and_then(|c| self.blocks.get_mut(c)) {
+            Some(c) => *c = block,
+            None => self.clipped = true,
+        }
+    }
+    /// Returns `OutOfBounds` for the object at `x`, `y` if anything was clipped since the last call.
+    fn check_clipped(&mut self, x: u16, y: u16) -> Result<(), DecodeError> {
+        let clipped = core::mem::replace(&mut self.clipped, false);
+        if clipped { Err(DecodeError::OutOfBounds { x, y }) } else { Ok(()) }
+    }
 }
 
+/// A rectangle filled with one block.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct LandChunk {
+    pub x: u16,
+    pub y: u16,
+    pub w: u16,
+    pub h: u16,
+    pub block: u16,
+}
 
-pub fn decode_land(buf: &mut [u8], mut src: &[u8]) {
-    decode_land_with(buf, core::iter::from_fn(|| {
-        if src.len() != 0 {
-            Some(decode_land_chunk(&mut src))
+impl LandChunk {
+    /// Writes the chunk out byte by byte, in the format `decode_land_chunk` reads.
+    pub fn encode(&self, header: &LevelHeader, mut out: impl FnMut(u8)) {
+        for c in [self.x, self.y, self.w, self.h].iter() {
+            c.to_be_bytes().iter().for_each(|&c| out(c));
+        }
+        if header.wide_blocks {
+            self.block.to_be_bytes().iter().for_each(|&c| out(c));
         } else {
-            None
+            out(self.block as u8);
         }
+    }
+}
+
+/// A kind of land, which land chunks painted with its block are shaped into.
+///
+/// Each tileset defines its own in `<name>_land.txt`.
+pub struct LandMaterial {
+    /// The block land chunks of this material are painted with.
+    pub block: u16,
+    /// Block for each combination of neighbouring land, indexed by top*8 + bottom*4 + left*2 + right.
+    pub edges: [u16; 16],
+    /// Blocks for inner corners cut into the top left, top right, bottom left and bottom right.
+    pub corners: Option<[u16; 4]>,
+    /// Chance out of 16 that a block with only its top open grows decoration.
+    pub decor_chance: u8,
+    /// Decorations to pick from, all equally likely.
+    pub decor: &'static [u16],
+}
+
+impl LandMaterial {
+    /// Whether a block is this material, shaped or not.
+    fn contains(&self, block: u16) -> bool {
+        block == self.block || self.edges.contains(&block) || self.corners.iter().flatten().any(|&c| c == block)
+    }
+}
+
+/// Decodes the land and objects of one layer.
+///
+/// Things reaching outside the map are clipped, and reported once everything else has been placed.
+/// Any other error stops decoding, leaving whatever was placed before it.
+pub fn decode_area(buf: &mut BlockMap, mut src: &[u8], header: &LevelHeader) -> Result<(), DecodeError> {
+    let len = read_u16(&mut src)? as usize;
+    let land = read_slice(&mut src, len)?;
+    let mut clipped = Ok(());
+    let mut check = |res: Result<(), DecodeError>| match res {
+        Err(DecodeError::OutOfBounds { .. }) => {
+            if clipped.is_ok() { clipped = res; }
+            Ok(())
+        }
+        res => res
+    };
+    check(decode_land(buf, land, header))?;
+    while !src.is_empty() {
+        check(decode_object(buf, &mut src, header))?;
+    }
+    clipped
+}
+
+pub fn decode_land_chunk(src: &mut &[u8], header: &LevelHeader) -> Result<LandChunk, DecodeError> {
+    Ok(LandChunk {
+        x: read_u16(src)?,
+        y: read_u16(src)?,
+        w: read_u16(src)?,
+        h: read_u16(src)?,
+        block: header.read_block(src)?,
+    })
+}
+
+
+pub fn decode_land(buf: &mut BlockMap, mut src: &[u8], header: &LevelHeader) -> Result<(), DecodeError> {
+    let mut error = None;
+    let res = decode_land_with(buf, header.land_materials(), core::iter::from_fn(|| {
+        if src.is_empty() { return None; }
+        decode_land_chunk(&mut src, header).map_err(|e| error = Some(e)).ok()
     }));
+    error.map_or(res, Err)
 }
 
-pub fn decode_land_with(buf: &mut [u8], mut src: impl Iterator<Item=[u8;5]>) {
+/// Places land chunks and shapes their edges, following the rules of each material.
+///
+/// Chunks reaching outside the map are clipped, and the first one is reported.
+pub fn decode_land_with(buf: &mut BlockMap, materials: &[LandMaterial], mut src: impl Iterator<Item=LandChunk>) -> Result<(), DecodeError> {
     let mut rand = RandState::new(0);
-    //while src.len() != 0 {
-    //    let [x,y,w,h,b] = decode_land_chunk(&mut src);
-    while let Some([x,y,w,h,b]) = src.next() {
-        for i in x..x+w {
-            for j in y..y+h {
-                buf[i as usize + j as usize * 256] = b;
-            }
-        }
-    }
-
-    for y in 0u8..=255 {
-        for x in 0u8..=255 {
-            let top =    buf[x as usize + y.saturating_sub(1) as usize * 256] != 0;
-            let bottom = buf[x as usize + y.saturating_add(1) as usize * 256] != 0;
-            let left =   buf[x.saturating_sub(1) as usize + y as usize * 256] != 0;
-            let right =  buf[x.saturating_add(1) as usize + y as usize * 256] != 0;
-
-            let this = &mut buf[x as usize + y as usize * 256];
-
-            if *this == 0x62 {
-                let bh = [0, 1, 3, 2][((top as usize) << 1) + bottom as usize] + 4;
-                let bl = [0, 1, 3, 2][((left as usize) << 1) + right as usize];
-                let b = (bh << 4) + bl;
-                *this = b;
-                if b == 0x52 {  // top soil
-                    let ty = rand.next() & 0x0F;
-                    if ty < 4 {
-                        buf[(x as usize) + (y as usize - 1) * 256] = 0x80 + ty as u8;
+    let mut res = Ok(());
+    while let Some(LandChunk { x, y, w, h, block }) = src.next() {
+        // Only the part inside the map is filled, so huge chunks can't stall decoding
+        let (right, bottom) = (x as usize + w as usize, y as usize + h as usize);
+        let (width, height) = (buf.width, buf.height());
+        buf.clipped |= right > width || bottom > height;
+        for i in x as usize..right.min(width) {
+            for j in y as usize..bottom.min(height) {
+                buf.set(i, j, block);
+            }
+        }
+        if buf.check_clipped(x, y).is_err() && res.is_ok() {
+            res = Err(DecodeError::OutOfBounds { x, y });
+        }
+    }
+
+    let (width, height) = (buf.width, buf.height());
+    for y in 0..height {
+        for x in 0..width {
+            let top =    buf.get(x, y.saturating_sub(1)) != 0;
+            let bottom = buf.get(x, (y + 1).min(height - 1)) != 0;
+            let left =   buf.get(x.saturating_sub(1), y) != 0;
+            let right =  buf.get((x + 1).min(width - 1), y) != 0;
+
+            let this = buf.get(x, y);
+
+            if let Some(material) = materials.iter().find(|c| c.block == this) {
+                let neighbors = (top as usize) << 3 | (bottom as usize) << 2 | (left as usize) << 1 | right as usize;
+                buf.set(x, y, material.edges[neighbors]);
+                if neighbors == 0b0111 && !material.decor.is_empty() {  // top soil
+                    let roll = (rand.next() & 0x0F) as usize;
+                    if roll < material.decor_chance as usize {
+                        let decor = material.decor[roll * material.decor.len() / material.decor_chance as usize];
+                        buf.set(x, y.wrapping_sub(1), decor);
                     }
                 }
-            } else if *this == 0x00 {
+            } else if this == 0x00 {
+                // Inner corners take the material of the block they're cut into
+                let mut corner = |cx: usize, cy: usize, which: usize| {
+                    let block = buf.get(cx, cy);
+                    let corners = materials.iter().find(|c| c.contains(block)).and_then(|c| c.corners);
+                    if let Some(corners) = corners {
+                        buf.set(cx, cy, corners[which]);
+                    }
+                };
                 if top && left {
-                    buf[(x as usize - 1) + (y as usize - 1) * 256] = 0x44;
+                    corner(x.wrapping_sub(1), y.wrapping_sub(1), 0);
                 }
                 if top && right {
-                    buf[(x as usize + 1) + (y as usize - 1) * 256] = 0x45;
+                    corner(x + 1, y.wrapping_sub(1), 1);
                 }
                 if bottom && left {
-                    buf[(x as usize - 1) + (y as usize + 1) * 256] = 0x54;
+                    corner(x.wrapping_sub(1), y + 1, 2);
                 }
                 if bottom && right {
-                    buf[(x as usize + 1) + (y as usize + 1) * 256] = 0x55;
+                    corner(x + 1, y + 1, 3);
                 }
             }
         }
     }
+    // Edge shaping running into the map's border isn't the level's fault
+    buf.clipped = false;
+    res
 }
 
-pub fn decode_object(buf: &mut [u8], i: &mut &[u8]) {
-    let id = i[0];
-    let y = i[1];
-    let x = i[2];
-    let params = i[3];
-    let b = params & 0x0F;
-    let a = params >> 4;
-    *i = &i[4..];
+/// Object ids with this bit set take two whole bytes of parameters, instead of a nibble each.
+pub const WIDE_PARAMS: u8 = 0x80;
+
+/// Decodes one object and places it.
+///
+/// Parts of the object outside the map are clipped, and reported as `OutOfBounds` afterwards.
+pub fn decode_object(buf: &mut BlockMap, i: &mut &[u8], header: &LevelHeader) -> Result<(), DecodeError> {
+    buf.clipped = false;
+    let id = read_u8(i)?;
+    let y = read_u16(i)?;
+    let x = read_u16(i)?;
+    let (a, b) = if id & WIDE_PARAMS != 0 {
+        (read_u8(i)?, read_u8(i)?)
+    } else {
+        let params = read_u8(i)?;
+        (params >> 4, params & 0x0F)
+    };
+    let (ox, oy) = (x, y);
+    let (x, y) = (x as usize, y as usize);
+    let id = id & !WIDE_PARAMS;
     match id {
         1 => {  // row of blocks
             let block = b;
             let width = a;
-            for x in x..x+width {
-                buf[x as usize + y as usize*256] = block;
+            for x in x..x + width as usize {
+                buf.set(x, y, block as u16);
             }
         },
         2 => {  // column of blocks
             let block = b;
             let height = a;
-            for y in y..y+height {
-                buf[x as usize + y as usize*256] = block;
+            for y in y..y + height as usize {
+                buf.set(x, y, block as u16);
             }
         },
-        5 => {  // land gentle slope
-            let height = b;
+        5 | 0x0C => {  // land gentle slope, or the same slope upside down as a roof
+            let height = b as usize;
             let is_up = (a) & 0x01 != 0;
             let is_filled = (a >> 1) & 0x01 != 0;
+            let is_roof = id == 0x0C;
+            let mut set = |x, row, block| set_slope_block(buf, y, is_roof, x, row, block);
             if is_up {
-                buf[x as usize + (y as usize) * 256] = 0x66;
+                set(x, y, 0x66);
                 for i in 0..height {
                     for j in 0..3 {
-                        buf[x as usize + (i * 2 + j) as usize + (y as usize - i as usize - 1) * 256] = 0x64 + j;
+                        set(x + i * 2 + j, y.wrapping_sub(i + 1), 0x64 + j as u16);
                     }
                     if is_filled {
                         for j in i*2..height*2+1 {
-                            buf[x as usize + (j + 1) as usize + (y as usize - i as usize) * 256] = 0x62;
+                            set(x + j + 1, y.wrapping_sub(i), 0x62);
                         }
                     }
                 }
                 if is_filled {
-                    buf[x as usize + (height as usize * 2 + 1) + (y as usize - height as usize) * 256] = 0x62;
+                    set(x + height * 2 + 1, y.wrapping_sub(height), 0x62);
                 }
-                buf[x as usize + 0 + (height as usize * 2) + (y as usize - height as usize - 1) * 256] = 0x64;
-                buf[x as usize + 1 + (height as usize * 2) + (y as usize - height as usize - 1) * 256] = 0x65;
+                set(x + height * 2, y.wrapping_sub(height + 1), 0x64);
+                set(x + 1 + height * 2, y.wrapping_sub(height + 1), 0x65);
             } else {
-                buf[x as usize + (y as usize) * 256] = 0x75;
-                buf[x as usize + 1 + (y as usize) * 256] = 0x76;
+                set(x, y, 0x75);
+                set(x + 1, y, 0x76);
                 for i in 0..height {
                     for j in 0..3 {
-                        buf[x as usize + (i * 2 + j + 1) as usize + (y as usize + i as usize + 1) * 256] = 0x74 + j;
+                        set(x + i * 2 + j + 1, y + i + 1, 0x74 + j as u16);
                     }
                     if is_filled {
                         for j in 0..i*2+3 {
-                            buf[x as usize + (j) as usize + (y as usize + i as usize + 2) * 256] = 0x62;
+                            set(x + j, y + i + 2, 0x62);
                         }
                     }
                     if is_filled {
-                        buf[x as usize + (y as usize + 1 as usize) * 256] = 0x62;
+                        set(x, y + 1, 0x62);
                     }
                 }
-                buf[x as usize + 1 + (height as usize * 2) + (y as usize + height as usize + 1) * 256] = 0x74;
+                set(x + 1 + height * 2, y + height + 1, 0x74);
             }
         },
-        6 => {  // land steep slope
-            let height = b;
+        6 | 0x0D => {  // land steep slope, or the same slope upside down as a roof
+            let height = b as usize;
             let is_up = (a) & 0x01 != 0;
             let is_filled = (a >> 1) & 0x01 != 0;
+            let is_roof = id == 0x0D;
+            let mut set = |x, row, block| set_slope_block(buf, y, is_roof, x, row, block);
             for i in 0..height {
                 for j in 0..2 {
                     if is_up {
-                        buf[x as usize + (i) as usize + (y as usize - i as usize + j as usize) * 256] = 0x46 + j * 0x10;
+                        set(x + i, (y + j).wrapping_sub(i), 0x46 + j as u16 * 0x10);
                     } else {
-                        buf[x as usize + (i) as usize + (y as usize + i as usize + j as usize) * 256] = 0x47 + j * 0x10;
+                        set(x + i, y + i + j, 0x47 + j as u16 * 0x10);
                     }
                 }
                 if is_filled {
                     if is_up {
                         for j in 0..i {
-                            buf[x as usize + (i) as usize + (y as usize - j as usize + 1) * 256] = 0x62;
+                            set(x + i, (y + 1).wrapping_sub(j), 0x62);
                         }
                     } else {
                         for j in i+1..height {
-                            buf[x as usize + (i) as usize + (y as usize + j as usize + 1) * 256] = 0x62;
+                            set(x + i, y + j + 1, 0x62);
                         }
                     }
                 }
@@ -174,15 +673,28 @@ pub fn decode_object(buf: &mut [u8], i: &mut &[u8]) {
             let width = a;
             for ry in 0..=height {
                 for rx in 0..=width {
-                    let block = i[0];
-                    *i = &i[1..];
-                    buf[x as usize + rx as usize + (y as usize + ry as usize)*256] = block;
+                    let block = header.read_block(i)?;
+                    buf.set(x + rx as usize, y + ry as usize, block);
                 }
             }
         },
+        0x0B => {   // vine or ladder column
+            let is_ladder = a & 0x01 != 0;
+            let height = read_u8(i)?;
+            for ry in 0..height {
+                let block = match (is_ladder, ry) {
+                    (false, 0) => 0x15,
+                    (false, c) if c == height - 1 => 0x17,
+                    (false, _) => 0x16,
+                    (true, 0) => 0x18,
+                    (true, _) => 0x19,
+                };
+                buf.set(x, y + ry as usize, block);
+            }
+        },
         0x0A => {   // Semisolid
-            let height = (b) + 1;   // min width = 2
-            let width = (a) + 1;
+            let height = b as usize + 1;   // min width = 2
+            let width = a as usize + 1;
             for ry in 0..=height {
                 for rx in 0..=width {
                     let block = if ry == 0 { 0x50 } else { 0x60 } | match rx {
@@ -190,10 +702,127 @@ pub fn decode_object(buf: &mut [u8], i: &mut &[u8]) {
                         c if c == width => 0x0F,
                         _ => 0x0E
                     };
-                    buf[x as usize + rx as usize + (y as usize + ry as usize)*256] = block;
+                    buf.set(x + rx, y + ry, block);
+                }
+            }
+        }
+        0x0E => {   // pipe, two blocks thick
+            let length = a as usize;
+            for i in 0..length {
+                let (mouth, body, at) = match b & 0x03 {
+                    0 => ([0x90, 0x91], [0xA0, 0xA1], (x, y + i)),
+                    1 => ([0xB0, 0xB1], [0xA0, 0xA1], (x, y + length - 1 - i)),
+                    2 => ([0x92, 0xA2], [0x93, 0xA3], (x + i, y)),
+                    _ => ([0x94, 0xA4], [0x93, 0xA3], (x + length - 1 - i, y)),
+                };
+                let blocks = if i == 0 { mouth } else { body };
+                let side = if b & 0x02 == 0 { (1, 0) } else { (0, 1) };
+                buf.set(at.0, at.1, blocks[0]);
+                buf.set(at.0 + side.0, at.1 + side.1, blocks[1]);
+            }
+        }
+        0x0F => {   // rope bridge
+            for rx in 0..a as usize {
+                buf.set(x + rx, y, 0x96);
+                buf.set(x + rx, y.wrapping_sub(1), 0x86);
+            }
+        }
+        0x10 => {   // arc of coins, peaking `b` blocks above the ends
+            let width = a as i32;
+            for rx in 0..width {
+                // parabola through both ends, from -1 to 1 across the arc
+                let t = (rx * 2 - (width - 1)) * 64 / (width - 1).max(1);
+                let height = (b as i32 * (64 * 64 - t * t) + 64 * 32) / (64 * 64);
+                buf.set(x + rx as usize, y + (b as i32 - height) as usize, 0x02);
+            }
+        }
+        0x11 => {   // rectangle of coins
+            for ry in 0..b as usize {
+                for rx in 0..a as usize {
+                    buf.set(x + rx, y + ry, 0x02);
+                }
+            }
+        }
+        0x12 => {   // stairs, rising to the right unless `b` is odd
+            let height = a as usize;
+            let block = header.read_block(i)?;
+            for step in 0..height {
+                let rx = if b & 0x01 == 0 { step } else { height - 1 - step };
+                for ry in height - 1 - step..height {
+                    buf.set(x + rx, y + ry, block);
                 }
             }
         }
-        _ => {}
+        0x13 => {   // rectangle filled with one block
+            let block = header.read_block(i)?;
+            for ry in 0..b as usize {
+                for rx in 0..a as usize {
+                    buf.set(x + rx, y + ry, block);
+                }
+            }
+        }
+        0x14 => {   // decorations standing on row `y`, spread over `a` blocks
+            let mut rand = RandState::new(((ox as i32) << 16) | oy as i32);
+            let mut rx = 0;
+            while rx < a as usize {
+                let roll = rand.next() & 0x0F;
+                match b {
+                    0 => {  // cacti, some of them tall
+                        if roll < 6 {
+                            buf.set(x + rx, y, 0x80);
+                        } else {
+                            let height = (roll & 0x03) as usize + 1;
+                            for ry in 0..height {
+                                buf.set(x + rx, y.wrapping_sub(ry), 0x84);
+                            }
+                            buf.set(x + rx, y.wrapping_sub(height), 0x85);
+                        }
+                    }
+                    1 => buf.set(x + rx, y, 0x81 + (roll % 3) as u16),  // dry brush
+                    _ => {  // sand dunes
+                        if rx + 1 >= a as usize { break; }
+                        buf.set(x + rx, y, 0x87);
+                        buf.set(x + rx + 1, y, 0x88);
+                        rx += 1;
+                    }
+                }
+                rx += 1 + (roll >> 2) as usize % 3;
+            }
+        }
+        0x15 => {   // door, two blocks tall
+            buf.set(x, y, 0x97);
+            buf.set(x, y + 1, 0xA7);
+        }
+        _ => return Err(DecodeError::UnknownObject(id)),
+    }
+    buf.check_clipped(ox, oy)
+}
+
+/// Places a slope block, mirrored vertically around row `y` for roofs.
+fn set_slope_block(buf: &mut BlockMap, y: usize, is_roof: bool, x: usize, row: usize, block: u16) {
+    if is_roof {
+        buf.set(x, (2 * y).wrapping_sub(row), roof_block(block));
+    } else {
+        buf.set(x, row, block);
+    }
+}
+
+/// Maps a slope block to its upside-down counterpart.
+///
+/// The fill blocks under a slope (0x66, 0x74, 0x56, 0x57) are full blocks, and so are their
+/// counterparts 0x36-0x39, which only differ in graphics.
+fn roof_block(block: u16) -> u16 {
+    match block {
+        0x46 => 0x30,
+        0x47 => 0x31,
+        0x64 => 0x32,
+        0x65 => 0x33,
+        0x75 => 0x34,
+        0x76 => 0x35,
+        0x66 => 0x36,
+        0x74 => 0x37,
+        0x56 => 0x38,
+        0x57 => 0x39,
+        c => c
     }
 }
@@ -0,0 +1,26 @@
+//! Integer trigonometry, with angles in 1/256ths of a turn.
+
+/// The first quarter of a sine wave, in 1/256ths.
+const QUARTER: [i32; 65] = [
+    0, 6, 13, 19, 25, 31, 38, 44, 50, 56, 62, 68, 74, 80, 86, 92,
+    98, 104, 109, 115, 121, 126, 132, 137, 142, 147, 152, 157, 162, 167, 172, 177,
+    181, 185, 190, 194, 198, 202, 206, 209, 213, 216, 220, 223, 226, 229, 231, 234,
+    237, 239, 241, 243, 245, 247, 248, 250, 251, 252, 253, 254, 255, 255, 256, 256,
+    256,
+];
+
+/// Sine of an angle, in 1/256ths.
+pub fn sin(angle: i32) -> i32 {
+    let i = (angle & 255) as usize;
+    match i >> 6 {
+        0 => QUARTER[i],
+        1 => QUARTER[128 - i],
+        2 => -QUARTER[i - 128],
+        _ => -QUARTER[256 - i],
+    }
+}
+
+/// Cosine of an angle, in 1/256ths.
+pub fn cos(angle: i32) -> i32 {
+    sin(angle + 64)
+}
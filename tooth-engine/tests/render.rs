@@ -0,0 +1,34 @@
+//! Checks the foreground renderer against the per-pixel one, which `examples/render_bench.rs` times it against.
+
+use tooth_engine::{state, framebuffer::Framebuffer, raster::Scanlines, vec2::vec2};
+
+#[test]
+fn render_matches_sampled() {
+    let fg = &mut unsafe { state::get() }.unwrap_level().foreground;
+    // Bouncing blocks are drawn on their own, so get some going
+    fg.bump(vec2(20, 40));
+    fg.bump(vec2(21, 41));
+    for _ in 0..3 {
+        fg.tick();
+    }
+    let mut sampled = Box::new(Framebuffer::new());
+    let mut tiles = Box::new(Framebuffer::new());
+    let size = fg.size() * 16;
+    // Cameras past every edge of the level too, on odd steps so they don't line up with blocks
+    for y in (-200..size.y + 50).step_by(101) {
+        for x in (-340..size.x + 20).step_by(149) {
+            let camera = vec2(x, y);
+            let scanlines = Scanlines::flat(camera);
+            // Anything left unpainted shows up as a difference
+            for (i, (a, b)) in sampled.iter_mut().zip(tiles.iter_mut()).enumerate() {
+                *a = 0xFF000000 | i as u32;
+                *b = *a;
+            }
+            fg.render_sampled(camera, &mut sampled);
+            fg.render_front(camera, &scanlines, &mut sampled);
+            fg.render(camera, &scanlines, &mut tiles);
+            fg.render_front(camera, &scanlines, &mut tiles);
+            assert!(sampled[..] == tiles[..], "renderers differ with the camera at {},{}", x, y);
+        }
+    }
+}
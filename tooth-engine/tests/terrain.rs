@@ -0,0 +1,58 @@
+//! Checks that broken level data is reported with the right `DecodeError`.
+
+use tooth_engine::terrain::{self, BlockMap, DecodeError, LevelHeader};
+use tooth_engine::vec2::vec2;
+
+/// An area 8 blocks wide and 4 tall, as land chunks then objects, without any land.
+fn decode(objects: &[u8]) -> (Result<(), DecodeError>, Vec<u16>) {
+    let mut blocks = vec![0; 8 * 4];
+    let mut src = vec![0, 0];
+    src.extend_from_slice(objects);
+    let res = terrain::decode_area(&mut BlockMap::new(&mut blocks, 8), &src, &LevelHeader::default());
+    (res, blocks)
+}
+
+#[test]
+fn header_round_trip() {
+    let header = LevelHeader { size: vec2(32, 16), water_level: Some(12), start: vec2(3, 12), ..Default::default() };
+    assert_eq!(LevelHeader::decode(&mut &header.encode()[..]), Ok(header));
+}
+
+#[test]
+fn header_bad_size() {
+    for &(width, height) in [(0, 16), (32, 0), (0x800, 0x800)].iter() {
+        let header = LevelHeader { size: vec2(width, height), start: vec2(0, 0), ..Default::default() };
+        assert_eq!(LevelHeader::decode(&mut &header.encode()[..]), Err(DecodeError::BadSize { width, height }));
+    }
+}
+
+#[test]
+fn truncated() {
+    let header = LevelHeader::default().encode();
+    assert_eq!(LevelHeader::decode(&mut &header[..LevelHeader::LEN - 1]), Err(DecodeError::Truncated));
+    // A row of blocks missing its parameters
+    assert_eq!(decode(&[0x01, 0x00, 0x01, 0x00]).0, Err(DecodeError::Truncated));
+}
+
+#[test]
+fn unknown_object() {
+    assert_eq!(decode(&[0x7F, 0x00, 0x01, 0x00, 0x02, 0x23]).0, Err(DecodeError::UnknownObject(0x7F)));
+}
+
+#[test]
+fn object_out_of_bounds() {
+    // A row of 4 blocks starting 2 blocks from the right edge, then one fully inside
+    let (res, blocks) = decode(&[0x01, 0x00, 0x01, 0x00, 0x06, 0x43, 0x01, 0x00, 0x03, 0x00, 0x00, 0x25]);
+    assert_eq!(res, Err(DecodeError::OutOfBounds { x: 6, y: 1 }));
+    assert_eq!(&blocks[8..16], &[0, 0, 0, 0, 0, 0, 3, 3]);
+    assert_eq!(&blocks[24..32], &[5, 5, 0, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn length_mismatch() {
+    let mut blocks = vec![0; 8 * 4];
+    // Says there are 16 bytes of land, but there are only 2
+    let src = [0x00, 0x10, 0x00, 0x00];
+    let res = terrain::decode_area(&mut BlockMap::new(&mut blocks, 8), &src, &LevelHeader::default());
+    assert_eq!(res, Err(DecodeError::LengthMismatch));
+}